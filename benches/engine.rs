@@ -0,0 +1,119 @@
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+use transaction_processing::{Config, Engine, InputRow, csv_reader, process_reader};
+
+const ROWS: u64 = 100_000;
+const CLIENTS: u64 = 1_000;
+
+/// Deterministic mix of deposits, withdrawals and disputes over `CLIENTS` clients.
+fn workload() -> Vec<InputRow> {
+    (1..=ROWS)
+        .map(|tx| {
+            let (transaction_type, transaction_id, amount) = match tx % 10 {
+                0..=5 => ("deposit", tx, Some("10.5".to_string())),
+                6..=8 => ("withdrawal", tx, Some("2.25".to_string())),
+                // one of the deposits of the block CLIENTS rows earlier, 0 (no
+                // such transaction) in the first blocks
+                _ => (
+                    "dispute",
+                    (tx - 9 + (tx / 10) % 6).saturating_sub(CLIENTS),
+                    None,
+                ),
+            };
+            // a dispute is filed by the client owning the deposit
+            InputRow {
+                transaction_type: transaction_type.to_string(),
+                client_id: (transaction_id % CLIENTS) as u16,
+                transaction_id,
+                amount,
+                ..InputRow::default()
+            }
+        })
+        .collect()
+}
+
+fn workload_csv(rows: &[InputRow]) -> Vec<u8> {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for row in rows {
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            row.transaction_type,
+            row.client_id,
+            row.transaction_id,
+            row.amount.as_deref().unwrap_or("")
+        ));
+    }
+    csv.into_bytes()
+}
+
+// a workload whose disputes miss their deposits would only time lookups
+fn check_workload(rows: &[InputRow]) {
+    let mut engine: Engine = Engine::default();
+    engine.apply_all(rows.iter().cloned());
+    let disputes = rows
+        .iter()
+        .filter(|row| row.transaction_type == "dispute" && row.transaction_id != 0)
+        .count();
+    assert!(disputes > 0);
+    assert_eq!(engine.disputed_transactions().len(), disputes);
+}
+
+fn bench_apply(c: &mut Criterion) {
+    let rows = workload();
+    check_workload(&rows);
+    let mut group = c.benchmark_group("engine");
+    group.throughput(Throughput::Elements(ROWS));
+    group.bench_function("apply", |b| {
+        b.iter_batched(
+            || rows.clone(),
+            |rows| {
+                let mut engine: Engine = Engine::default();
+                for row in rows {
+                    engine.apply(row);
+                }
+                black_box(engine)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_csv_parse(c: &mut Criterion) {
+    let csv = workload_csv(&workload());
+    let mut group = c.benchmark_group("csv");
+    group.throughput(Throughput::Elements(ROWS));
+    group.bench_function("parse", |b| {
+        b.iter(|| {
+            let parsed = csv_reader(csv.as_slice())
+                .deserialize::<InputRow>()
+                .flatten()
+                .count();
+            black_box(parsed)
+        })
+    });
+    group.finish();
+}
+
+// end to end from CSV bytes, through InputRow and through the fast path
+fn bench_process(c: &mut Criterion) {
+    let csv = workload_csv(&workload());
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(ROWS));
+    for (name, fast_path) in [("regular", false), ("fast_path", true)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut engine: Engine = Engine::with_config(Config {
+                    fast_path,
+                    ..Config::default()
+                });
+                process_reader(csv.as_slice(), &mut engine).expect("process workload");
+                black_box(engine)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_apply, bench_csv_parse, bench_process);
+criterion_main!(benches);
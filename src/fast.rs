@@ -0,0 +1,237 @@
+use crate::{
+    Amount, ApplyError, BUILTIN_TYPES, ClientID, DEFAULT_MAX_FIELD_LEN, Engine, Stats,
+    TransactionID, amount_field, duplicate_header, elapsed_since, input_reader, open_amount,
+};
+use std::borrow::Cow;
+use std::io;
+use std::str;
+use std::time::Instant;
+
+impl<A: Amount> Engine<A> {
+    /// True when [`Config::fast_path`](crate::Config::fast_path) can be
+    /// honoured: no aliases, consecutive dedup, time window, schema version
+    /// check, panic catching, dialect sniffing, truncation detection,
+    /// explained transaction or custom handlers, which all need the full row.
+    pub fn fast_path_applies(&self) -> bool {
+        let config = &self.config;
+        config.aliases.is_empty()
+            && !config.dedup_consecutive_rows
+            && config.since.is_none()
+            && config.until.is_none()
+            && config.require_schema_version.is_none()
+            && !config.catch_panics
+            && !config.auto_dialect
+            && !config.detect_truncation
+            && config.explain_tx.is_none()
+            && self.handlers.is_empty()
+    }
+}
+
+// column positions looked up once from the header
+struct Columns {
+    ty: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    amount_minor: Option<usize>,
+    currency: Option<usize>,
+}
+
+// what a record turned into before being applied
+enum Parsed<'r> {
+    Malformed,
+    Rejected(ApplyError),
+    Row {
+        ty: &'r str,
+        client: ClientID,
+        tx: TransactionID,
+        amount: Option<&'r str>,
+        amount_minor: Option<&'r str>,
+        currency: Option<&'r str>,
+    },
+}
+
+// mirrors the checks and deserialization of `process_records` on a borrowed
+// record: too long fields, client id overflow, blank ids in strict mode
+fn parse_record<'r>(
+    record: &'r csv::ByteRecord,
+    columns: &Columns,
+    max_field_len: usize,
+    strict: bool,
+) -> Parsed<'r> {
+    // the regular path drops records that are not valid UTF-8
+    if str::from_utf8(record.as_slice()).is_err() {
+        return Parsed::Malformed;
+    }
+    if let Some(len) = record
+        .iter()
+        .map(<[u8]>::len)
+        .find(|&len| len > max_field_len)
+    {
+        return Parsed::Rejected(ApplyError::FieldTooLong { len });
+    }
+    let field = |index: Option<usize>| {
+        index
+            .and_then(|i| record.get(i))
+            .and_then(|bytes| str::from_utf8(bytes).ok())
+    };
+    // empty fields deserialize to None
+    let optional = |index: Option<usize>| field(index).filter(|value| !value.is_empty());
+
+    let raw_client = field(columns.client);
+    let raw_tx = field(columns.tx);
+    let client = raw_client.and_then(|v| v.parse().ok());
+    let tx = raw_tx.and_then(|v| v.parse().ok());
+    let (Some(ty), Some(client), Some(tx)) = (field(columns.ty), client, tx) else {
+        if let Some(client) = raw_client
+            .and_then(|v| v.parse::<u64>().ok())
+            .filter(|&client| client > ClientID::MAX.into())
+        {
+            return Parsed::Rejected(ApplyError::ClientIdOutOfRange { client });
+        }
+        let blank = |value: Option<&str>| value.is_some_and(|v| v.trim().is_empty());
+        return match (blank(raw_client), blank(raw_tx)) {
+            (true, _) if strict => Parsed::Rejected(ApplyError::MissingField { field: "client" }),
+            (_, true) if strict => Parsed::Rejected(ApplyError::MissingField { field: "tx" }),
+            _ => Parsed::Malformed,
+        };
+    };
+    Parsed::Row {
+        ty,
+        client,
+        tx,
+        amount: optional(columns.amount),
+        amount_minor: optional(columns.amount_minor),
+        currency: optional(columns.currency),
+    }
+}
+
+// Same outcome as `process_records` in lib.rs, reusing one ByteRecord and
+// borrowing every field from it; only unusual type spellings, new type
+// names and errors allocate. The caller checks `fast_path_applies` first.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "process", skip_all, fields(rows = tracing::field::Empty))
+)]
+pub(crate) fn process_records<A, R>(reader: R, engine: &mut Engine<A>) -> Result<Stats, ApplyError>
+where
+    A: Amount,
+    R: io::BufRead,
+{
+    let mut stats = Stats::default();
+    let max_field_len = engine.config.max_field_len.unwrap_or(DEFAULT_MAX_FIELD_LEN);
+    let mut reader = input_reader(reader, &engine.config);
+    let headers = match reader.headers() {
+        Ok(headers) => headers,
+        Err(_) => return Ok(stats),
+    };
+    if let Some(name) = duplicate_header(headers) {
+        return Err(ApplyError::DuplicateHeader {
+            name: name.to_string(),
+        });
+    }
+    let position = |name: &str| headers.iter().position(|h| h == name);
+    let columns = Columns {
+        ty: position("type"),
+        client: position("client"),
+        tx: position("tx"),
+        amount: position("amount"),
+        amount_minor: position("amount_minor"),
+        currency: position("currency"),
+    };
+    let scale = engine.config.input_scale();
+    let precision = engine.config.precision;
+    let timings = engine.config.timings;
+    let ignored_before = engine.ignored;
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let parse_started = timings.then(Instant::now);
+        let read = reader.read_byte_record(&mut record);
+        if let Ok(false) = read {
+            break;
+        }
+        stats.rows += 1;
+        // malformed rows are skipped
+        let parsed = match read {
+            Ok(_) => parse_record(&record, &columns, max_field_len, engine.config.strict),
+            Err(_) => Parsed::Malformed,
+        };
+        stats.parse_time += elapsed_since(parse_started);
+        let (result, transaction_id) = match parsed {
+            Parsed::Malformed => {
+                stats.malformed += 1;
+                continue;
+            }
+            Parsed::Rejected(e) => (Err(e), None),
+            Parsed::Row {
+                ty: raw_type,
+                client,
+                tx,
+                amount,
+                amount_minor,
+                currency,
+            } => {
+                let ty = if raw_type.bytes().all(|b| b.is_ascii_lowercase()) {
+                    Cow::Borrowed(raw_type)
+                } else {
+                    Cow::Owned(engine.normalize_type(raw_type))
+                };
+                match stats.types.get_mut(ty.as_ref()) {
+                    Some(count) => *count += 1,
+                    None => {
+                        stats.types.insert(ty.to_string(), 1);
+                    }
+                }
+                let apply_started = timings.then(Instant::now);
+                let result = match engine.admits_client(client) {
+                    Ok(true) => engine
+                        .check_currency(client, currency)
+                        .and_then(|currency| {
+                            let applied = match ty.as_ref() {
+                                "open" => open_amount(amount, amount_minor, tx, scale, precision)
+                                    .and_then(|amount| {
+                                        engine.apply_builtin("open", client, tx, amount)
+                                    }),
+                                ty @ ("deposit" | "withdrawal") => {
+                                    amount_field(amount, amount_minor, tx, scale, precision)
+                                        .and_then(|amount| {
+                                            engine.apply_builtin(ty, client, tx, amount)
+                                        })
+                                }
+                                ty if BUILTIN_TYPES.contains(&ty) => {
+                                    engine.apply_builtin(ty, client, tx, None)
+                                }
+                                _ => Err(ApplyError::UnknownTransaction {
+                                    ty: raw_type.trim().to_string(),
+                                }),
+                            }?;
+                            if applied {
+                                engine.record_currency(client, currency);
+                            }
+                            Ok(())
+                        }),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                stats.apply_time += elapsed_since(apply_started);
+                (result, Some(tx))
+            }
+        };
+        if let Err(e) = result {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(error = %e, "row rejected");
+            if engine.config.strict {
+                return Err(e);
+            }
+            stats.rejected += 1;
+        }
+        if transaction_id.is_some() && transaction_id == engine.config.as_of_tx {
+            break;
+        }
+    }
+    stats.ignored = engine.ignored - ignored_before;
+    stats.pending = engine.pending_count() as u64;
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("rows", stats.rows);
+    Ok(stats)
+}
@@ -0,0 +1,1971 @@
+use chrono::{DateTime, FixedOffset};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod fast;
+mod output;
+mod quote_guard;
+mod rate_limit;
+mod snapshot;
+mod validate;
+
+use quote_guard::{MAX_LINE_FIELDS, QuoteGuard};
+
+pub use output::{
+    CsvSink, OutputRow, OutputSink, TableSink, VecSink, rows_checksum, write_held_detail,
+    write_rows, write_to_sink, write_transactions,
+};
+pub use rate_limit::RateLimiter;
+pub use snapshot::{SnapshotError, StateDump};
+pub use validate::{ValidationReport, validate_reader};
+
+pub type ClientID = u16;
+pub type TransactionID = u64;
+pub type Currency = Decimal;
+
+/// Decimal places used for amounts unless configured otherwise.
+pub const DEFAULT_SCALE: u32 = 4;
+
+/// Field length limit unless configured otherwise; far above any valid value.
+pub const DEFAULT_MAX_FIELD_LEN: usize = 1024;
+
+/// Type names of the legacy ledger export and the types they map to, for
+/// use as [`Config::aliases`].
+pub const LEGACY_TYPE_ALIASES: [(&str, &str); 2] = [("credit", "deposit"), ("debit", "withdrawal")];
+
+// types handled by the engine itself, custom handlers only see the rest
+const BUILTIN_TYPES: [&str; 8] = [
+    "open",
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "reset",
+    "void",
+];
+
+/// Numeric type used for balances and transaction amounts.
+///
+/// The engine only needs ordering, addition and subtraction, plus a way to
+/// parse the `amount` column. `Decimal` is the default.
+pub trait Amount:
+    Copy + Ord + Default + fmt::Debug + Add<Output = Self> + Sub<Output = Self> + AddAssign + SubAssign
+{
+    fn zero() -> Self {
+        Self::default()
+    }
+
+    fn parse(amount: &str) -> Result<Self, String>;
+
+    /// Parses an amount allowing at most `scale` decimal places. Types with
+    /// a fixed precision can ignore `scale`.
+    fn parse_with_scale(amount: &str, scale: u32) -> Result<Self, String> {
+        let _ = scale;
+        Self::parse(amount)
+    }
+
+    /// Like [`Amount::parse_with_scale`], handling amounts with more than
+    /// `scale` decimal places as `policy` says. Types that do not override
+    /// it only support [`PrecisionPolicy::Reject`] and fail on any other.
+    fn parse_with_policy(
+        amount: &str,
+        scale: u32,
+        policy: PrecisionPolicy,
+    ) -> Result<Self, String> {
+        if policy != PrecisionPolicy::Reject {
+            return Err(format!("precision policy {:?} is not supported", policy));
+        }
+        Self::parse_with_scale(amount, scale)
+    }
+
+    /// Converts a configured [`Currency`] value, e.g. the overdraft limit,
+    /// into this type's units. No default: only the type knows whether it
+    /// counts whole units or minor ones.
+    fn from_currency(value: Currency) -> Self;
+}
+
+impl Amount for Decimal {
+    fn zero() -> Self {
+        Decimal::ZERO
+    }
+
+    fn parse(amount: &str) -> Result<Self, String> {
+        parse_amount(amount)
+    }
+
+    fn parse_with_scale(amount: &str, scale: u32) -> Result<Self, String> {
+        parse_amount_with_scale(amount, scale)
+    }
+
+    fn parse_with_policy(
+        amount: &str,
+        scale: u32,
+        policy: PrecisionPolicy,
+    ) -> Result<Self, String> {
+        parse_amount_with_policy(amount, scale, policy)
+    }
+
+    fn from_currency(value: Currency) -> Self {
+        value
+    }
+}
+
+/// One row of the input CSV, as read before any validation.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct InputRow {
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    #[serde(rename = "client")]
+    pub client_id: ClientID,
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionID,
+    /// Only read for deposits and withdrawals. Disputes, resolves,
+    /// chargebacks and resets ignore it, so an empty field and a missing
+    /// one are the same to them.
+    #[serde(default)]
+    pub amount: Option<String>,
+    /// Amount as an integer count of minor units at the input scale, e.g.
+    /// `12345` for `1.2345` at scale 4. Mutually exclusive with `amount`.
+    #[serde(default)]
+    pub amount_minor: Option<String>,
+    /// Optional RFC 3339 time of the transaction, only used for filtering.
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// Optional currency code. The first one seen for a client becomes its
+    /// account's currency, rows naming another one are rejected.
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+/// Typed alternative to [`InputRow`] for library callers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Creates the account, optionally with a starting balance.
+    Open {
+        client: ClientID,
+        tx: TransactionID,
+        amount: Option<Currency>,
+    },
+    Deposit {
+        client: ClientID,
+        tx: TransactionID,
+        amount: Currency,
+    },
+    Withdrawal {
+        client: ClientID,
+        tx: TransactionID,
+        amount: Currency,
+    },
+    Dispute {
+        client: ClientID,
+        tx: TransactionID,
+    },
+    Resolve {
+        client: ClientID,
+        tx: TransactionID,
+    },
+    Chargeback {
+        client: ClientID,
+        tx: TransactionID,
+    },
+    Reset {
+        client: ClientID,
+        tx: TransactionID,
+    },
+    Void {
+        client: ClientID,
+        tx: TransactionID,
+    },
+}
+
+impl From<Command> for InputRow {
+    fn from(command: Command) -> Self {
+        let (ty, client_id, transaction_id, amount) = match command {
+            Command::Open { client, tx, amount } => ("open", client, tx, amount),
+            Command::Deposit { client, tx, amount } => ("deposit", client, tx, Some(amount)),
+            Command::Withdrawal { client, tx, amount } => ("withdrawal", client, tx, Some(amount)),
+            Command::Dispute { client, tx } => ("dispute", client, tx, None),
+            Command::Resolve { client, tx } => ("resolve", client, tx, None),
+            Command::Chargeback { client, tx } => ("chargeback", client, tx, None),
+            Command::Reset { client, tx } => ("reset", client, tx, None),
+            Command::Void { client, tx } => ("void", client, tx, None),
+        };
+        InputRow {
+            transaction_type: ty.to_string(),
+            client_id,
+            transaction_id,
+            amount: amount.map(|amount| amount.to_string()),
+            ..InputRow::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Account<A = Currency> {
+    pub available: A,
+    pub held: A,
+    pub locked: bool,
+    /// Deposits and the opening balance minus withdrawals over the
+    /// account's lifetime; disputes, chargebacks and resets leave it alone.
+    pub net_movement: A,
+    // sequence number of the last row that touched this account
+    pub(crate) last_activity: u64,
+    // disputes placed on this account over its lifetime
+    #[serde(default)]
+    pub(crate) disputes: u64,
+    // currency of the first row that named one
+    #[serde(default)]
+    pub(crate) currency: Option<String>,
+}
+
+impl<A: Amount> Account<A> {
+    /// Account with the given balances, for seeding engine state in tests.
+    #[cfg(test)]
+    pub fn new(available: A, held: A, locked: bool) -> Self {
+        Account {
+            available,
+            held,
+            locked,
+            ..Account::default()
+        }
+    }
+
+    /// Currency the account transacts in, if any row named one.
+    pub fn currency(&self) -> Option<&str> {
+        self.currency.as_deref()
+    }
+
+    /// `available + held`, also once locked. Funds a chargeback removes
+    /// from the system are never part of it; under
+    /// [`ChargebackMode::Refund`] they are paid back to `available` and
+    /// counted there. Under [`DisputeMode::HoldOnly`] the hold is credit on
+    /// top of `available` and is included all the same.
+    pub fn total(&self) -> A {
+        self.available + self.held
+    }
+
+    /// Balance if every open dispute is decided against the client, with
+    /// disputes placed under `mode`.
+    pub fn pessimistic_balance(&self, mode: DisputeMode) -> A {
+        match mode {
+            DisputeMode::Debit => self.available,
+            // a chargeback still has to take the held funds out of available
+            DisputeMode::HoldOnly => self.available - self.held,
+        }
+    }
+
+    /// Balance if every open dispute is decided in the client's favour,
+    /// with disputes placed under `mode`.
+    pub fn optimistic_balance(&self, mode: DisputeMode) -> A {
+        match mode {
+            DisputeMode::Debit => self.total(),
+            // a resolve only releases the credit line
+            DisputeMode::HoldOnly => self.available,
+        }
+    }
+
+    /// Ordering key for recency: higher means touched more recently.
+    pub fn last_activity(&self) -> u64 {
+        self.last_activity
+    }
+}
+
+/// What a recorded [`Transaction`] was created by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    Reset,
+}
+
+impl TransactionKind {
+    /// The lowercase name used in the input type column.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+            TransactionKind::Reset => "reset",
+        }
+    }
+}
+
+/// A deposit, withdrawal or reset recorded by the engine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction<A = Currency> {
+    pub(crate) client_id: ClientID,
+    pub(crate) kind: TransactionKind,
+    pub(crate) amount: A,
+    pub(crate) disputed: bool,
+    #[serde(default)]
+    pub(crate) charged_back: bool,
+}
+
+impl<A: Copy> Transaction<A> {
+    pub fn client_id(&self) -> ClientID {
+        self.client_id
+    }
+
+    pub fn kind(&self) -> TransactionKind {
+        self.kind
+    }
+
+    pub fn amount(&self) -> A {
+        self.amount
+    }
+
+    /// True while a dispute on this transaction is open.
+    pub fn is_disputed(&self) -> bool {
+        self.disputed
+    }
+}
+
+/// Reason a row was rejected by [`Engine::try_apply`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyError {
+    UnknownTransaction {
+        ty: String,
+    },
+    ClientIdOutOfRange {
+        client: u64,
+    },
+    /// The input's `schema_version` column is missing (`found: None`) or
+    /// holds a version other than the required one.
+    SchemaVersionMismatch {
+        expected: u32,
+        found: Option<String>,
+    },
+    /// A deposit or withdrawal reused a recorded transaction id with a
+    /// different amount.
+    DuplicateAmountMismatch {
+        tx: TransactionID,
+    },
+    /// A deposit or withdrawal without a valid positive amount.
+    InvalidAmount {
+        tx: TransactionID,
+    },
+    /// Applying the row panicked, only reported with [`Config::catch_panics`].
+    Panicked {
+        tx: TransactionID,
+    },
+    /// A field of the row is longer than [`Config::max_field_len`] bytes.
+    /// Fields are cut while reading, so `len` is at most one past the limit.
+    FieldTooLong {
+        len: usize,
+    },
+    /// The last record looks cut off, see [`Config::detect_truncation`].
+    TruncatedInput {
+        row: u64,
+    },
+    /// A required field is empty or only whitespace, reported in strict
+    /// mode; such rows are otherwise dropped as malformed.
+    MissingField {
+        field: &'static str,
+    },
+    /// A deposit or withdrawal for a client without an `open` row, see
+    /// [`Config::require_open`].
+    AccountNotOpen {
+        client: ClientID,
+    },
+    /// The header names the same column more than once.
+    DuplicateHeader {
+        name: String,
+    },
+    /// A dispute against a client that already had as many as
+    /// [`Config::max_disputes`].
+    DisputeLimitExceeded {
+        client: ClientID,
+    },
+    /// The row names another currency than the client's account uses.
+    CurrencyMismatch {
+        client: ClientID,
+        expected: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApplyError::UnknownTransaction { ty } => write!(f, "unknown transaction type {:?}", ty),
+            ApplyError::ClientIdOutOfRange { client } => {
+                write!(f, "client id {} is out of range", client)
+            }
+            ApplyError::SchemaVersionMismatch {
+                expected,
+                found: None,
+            } => write!(
+                f,
+                "input has no schema_version column, version {} is required",
+                expected
+            ),
+            ApplyError::SchemaVersionMismatch {
+                expected,
+                found: Some(found),
+            } => write!(
+                f,
+                "input schema version {:?} does not match required version {}",
+                found, expected
+            ),
+            ApplyError::DuplicateAmountMismatch { tx } => {
+                write!(f, "transaction {} repeated with a different amount", tx)
+            }
+            ApplyError::MissingField { field } => write!(f, "missing value for {}", field),
+            ApplyError::AccountNotOpen { client } => {
+                write!(f, "client {} has no open account", client)
+            }
+            ApplyError::DuplicateHeader { name } => {
+                write!(f, "column {} appears more than once in the header", name)
+            }
+            ApplyError::DisputeLimitExceeded { client } => {
+                write!(f, "client {} has reached the dispute limit", client)
+            }
+            ApplyError::CurrencyMismatch {
+                client,
+                expected,
+                found,
+            } => write!(
+                f,
+                "client {} transacts in {}, the row is in {}",
+                client, expected, found
+            ),
+            ApplyError::TruncatedInput { row } => {
+                write!(
+                    f,
+                    "input looks truncated, last record {} is incomplete",
+                    row
+                )
+            }
+            ApplyError::FieldTooLong { len } => {
+                write!(f, "field of {} bytes exceeds the length limit", len)
+            }
+            ApplyError::Panicked { tx } => write!(f, "panic while applying transaction {}", tx),
+            ApplyError::InvalidAmount { tx } => {
+                write!(f, "transaction {} has an invalid amount", tx)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+/// Reason two engines could not be combined by [`Engine::merge`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    OverlappingClient(ClientID),
+    OverlappingTransaction(TransactionID),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::OverlappingClient(id) => write!(f, "client {} exists in both engines", id),
+            MergeError::OverlappingTransaction(id) => {
+                write!(f, "transaction {} exists in both engines", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// How a dispute sources the funds it places on hold.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeMode {
+    /// Held funds are taken out of `available` (the spec behaviour).
+    #[default]
+    Debit,
+    /// Held funds come from a separate credit line, `available` is untouched
+    /// until a chargeback removes the funds.
+    HoldOnly,
+}
+
+/// Where a chargeback sends the disputed funds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChargebackMode {
+    /// The funds leave the system (the spec behaviour).
+    #[default]
+    Reverse,
+    /// The funds are paid back to the client's `available` balance.
+    Refund,
+}
+
+/// What to do with an amount that has more decimal places than the input
+/// scale. An amount that becomes zero is rejected under every policy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrecisionPolicy {
+    /// The row's amount is invalid (the spec behaviour).
+    #[default]
+    Reject,
+    /// Rounded to the scale, halves away from zero.
+    Round,
+    /// The extra decimal places are dropped.
+    Truncate,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Admin operations (reset) are ignored unless explicitly enabled.
+    pub allow_admin_ops: bool,
+    /// Drop a dispute/resolve/chargeback identical to the row right before it.
+    pub dedup_consecutive_rows: bool,
+    /// Stop processing at the first rejected row instead of skipping it.
+    pub strict: bool,
+    /// Extra names for transaction types, e.g. `dep` -> `deposit`.
+    /// Keys are matched after type normalization.
+    pub aliases: HashMap<String, String>,
+    /// Highest accepted client id; rows for larger ids are rejected.
+    pub max_client_id: Option<ClientID>,
+    /// Let disputes and resolves run on locked accounts. Deposits,
+    /// withdrawals and chargebacks stay blocked.
+    pub allow_dispute_when_locked: bool,
+    pub dispute_mode: DisputeMode,
+    /// Either way the account is locked by a chargeback.
+    pub chargeback_mode: ChargebackMode,
+    /// Rows timestamped before this are skipped by the processing loop.
+    pub since: Option<DateTime<FixedOffset>>,
+    /// Rows timestamped after this are skipped by the processing loop.
+    pub until: Option<DateTime<FixedOffset>>,
+    /// Also skip rows without a timestamp when `since` or `until` is set.
+    pub exclude_untimestamped: bool,
+    /// Stop processing right after the first row carrying this transaction
+    /// id, giving balances as of that transaction.
+    pub as_of_tx: Option<TransactionID>,
+    /// Park disputes, resolves and chargebacks for transactions not seen yet,
+    /// up to this many rows, and replay them once the transaction arrives.
+    /// `None` drops them like any other reference to an unknown transaction.
+    pub pending_limit: Option<usize>,
+    /// Require every row to carry this value in a `schema_version` column.
+    /// A missing column or a different version aborts processing.
+    pub require_schema_version: Option<u32>,
+    /// Decimal places accepted in input amounts, [`DEFAULT_SCALE`] if unset.
+    pub input_scale: Option<u32>,
+    /// Reject a repeated deposit or withdrawal id whose amount differs from
+    /// the recorded one, instead of silently dropping it.
+    pub check_duplicate_amounts: bool,
+    /// Only apply rows for these clients; rows for others are skipped
+    /// silently, as if they were not in the input.
+    pub account_filter: Option<HashSet<ClientID>>,
+    /// Turn a panic while applying a row into [`ApplyError::Panicked`] and
+    /// carry on with the next row. The panicking row may be half applied.
+    pub catch_panics: bool,
+    /// Let deposits land on locked accounts. Everything else stays blocked.
+    pub locked_allows_deposit: bool,
+    /// Detect a `;`, tab or `|` delimiter from the header line when the
+    /// input is not comma separated.
+    pub auto_dialect: bool,
+    /// Longest accepted field in bytes, [`DEFAULT_MAX_FIELD_LEN`] if unset.
+    /// Rows with a longer field are rejected before being parsed.
+    pub max_field_len: Option<usize>,
+    /// Fail with [`ApplyError::TruncatedInput`] when the input does not end
+    /// with a line break and its last record is malformed, which usually
+    /// means the file was cut off. A cut that still leaves a valid row, e.g.
+    /// inside the amount, cannot be told apart from a complete file.
+    pub detect_truncation: bool,
+    /// Lines starting with this byte are skipped, e.g. `b'#'`.
+    pub comment: Option<u8>,
+    /// Reject a dispute when `available` no longer covers the disputed
+    /// amount, e.g. because it was withdrawn, instead of going negative.
+    pub dispute_requires_funds: bool,
+    /// Parse rows straight from the raw CSV record in [`process_reader`],
+    /// without building an [`InputRow`] per row. Only used when nothing
+    /// configured needs the row itself, see [`Engine::fast_path_applies`];
+    /// the results are the same either way.
+    pub fast_path: bool,
+    /// Measure time spent parsing and applying rows into [`Stats`]. Off by
+    /// default, it reads the clock twice per row.
+    pub timings: bool,
+    /// Reject deposits and withdrawals for clients whose account was not
+    /// created by an `open` row first, instead of creating it on the fly.
+    pub require_open: bool,
+    /// Clients allowed to dispute any client's deposit on their behalf,
+    /// e.g. operator ids. The hold is placed on the depositing client.
+    pub dispute_agents: HashSet<ClientID>,
+    /// Let withdrawals draw `available` down to minus this amount instead of
+    /// ignoring those that exceed it, as for a credit line.
+    pub overdraft_limit: Option<Currency>,
+    /// Record every row referencing this transaction id and its effect on
+    /// the balances, see [`Engine::explained`].
+    pub explain_tx: Option<TransactionID>,
+    /// Most disputes an account can receive over its lifetime; further
+    /// ones are rejected. Counted on the client owning the deposit, also
+    /// when a dispute agent files it, and kept across a reset.
+    pub max_disputes: Option<u64>,
+    /// How amounts with more decimal places than the input scale are read.
+    pub precision: PrecisionPolicy,
+}
+
+impl Config {
+    pub(crate) fn input_scale(&self) -> u32 {
+        self.input_scale.unwrap_or(DEFAULT_SCALE)
+    }
+
+    // Some(false) if the row falls outside since/until, None if its timestamp is invalid
+    fn in_time_window(&self, timestamp: Option<&str>) -> Option<bool> {
+        if self.since.is_none() && self.until.is_none() {
+            return Some(true);
+        }
+        let Some(timestamp) = timestamp.filter(|t| !t.is_empty()) else {
+            return Some(!self.exclude_untimestamped);
+        };
+        let at = DateTime::parse_from_rfc3339(timestamp).ok()?;
+        let after_start = self.since.is_none_or(|since| at >= since);
+        let before_end = self.until.is_none_or(|until| at <= until);
+        Some(after_start && before_end)
+    }
+}
+
+/// What happened to one row referencing [`Config::explain_tx`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplainEvent<A = Currency> {
+    /// Normalized row type, e.g. `dispute`.
+    pub ty: String,
+    /// Client named on the row.
+    pub client: ClientID,
+    /// Client owning the transaction, whose balances are reported below.
+    pub owner: ClientID,
+    pub outcome: ExplainOutcome,
+    /// Change of the owner's available balance caused by the row.
+    pub available: A,
+    /// Change of the owner's held balance caused by the row.
+    pub held: A,
+}
+
+/// Whether an explained row changed anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExplainOutcome {
+    Applied,
+    /// Accepted but without effect, see [`Engine::ignored_count`].
+    Ignored,
+    Rejected(ApplyError),
+}
+
+/// Applies rows of a transaction type the engine does not know, see
+/// [`Engine::register_handler`].
+pub trait TransactionHandler<A = Currency>: Send + Sync {
+    fn handle(&self, engine: &mut Engine<A>, row: &InputRow) -> Result<(), ApplyError>;
+}
+
+#[derive(Default)]
+pub struct Engine<A = Currency> {
+    // ordered maps, so everything iterating them is deterministic
+    accounts: BTreeMap<ClientID, Account<A>>,
+    transactions: BTreeMap<TransactionID, Transaction<A>>,
+    config: Config,
+    last_row: Option<InputRow>,
+    activity: u64,
+    applied_batches: BTreeSet<String>,
+    // dispute family rows waiting for their transaction, in arrival order
+    pending: BTreeMap<TransactionID, Vec<(ClientID, String)>>,
+    // rows in `pending`, kept alongside so parking does not have to count
+    parked: usize,
+    // starting state of accounts created by the engine
+    account_template: Account<A>,
+    // built-in rows that were accepted but had no effect
+    ignored: u64,
+    // custom transaction types by normalized name
+    handlers: HashMap<String, Arc<dyn TransactionHandler<A>>>,
+    // rows referencing config.explain_tx, in input order
+    explained: Vec<ExplainEvent<A>>,
+    // lets tests make try_apply panic on a given transaction
+    #[cfg(test)]
+    panic_on_tx: Option<TransactionID>,
+}
+
+impl<A: Amount> Engine<A> {
+    pub fn with_config(config: Config) -> Self {
+        Engine {
+            config,
+            ..Engine::default()
+        }
+    }
+
+    /// Starts new accounts from `template` instead of all-zero balances,
+    /// e.g. to grant every client a starting credit.
+    pub fn with_account_template(self, template: Account<A>) -> Self {
+        Engine {
+            account_template: template,
+            ..self
+        }
+    }
+
+    /// Sends rows of type `ty` to `handler`. Built-in types always win, so
+    /// registering e.g. `deposit` has no effect.
+    pub fn register_handler<H>(&mut self, ty: &str, handler: H)
+    where
+        H: TransactionHandler<A> + 'static,
+    {
+        let ty = self.normalize_type(ty);
+        self.handlers.insert(ty, Arc::new(handler));
+    }
+
+    /// The account of `client_id`, created from the account template if the
+    /// client has not been seen yet. Meant for [`TransactionHandler`]s.
+    pub fn account_mut(&mut self, client_id: ClientID) -> &mut Account<A> {
+        self.get_or_create_account(client_id)
+    }
+
+    /// The configuration the engine was created with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Accounts ordered by client id.
+    pub fn accounts(&self) -> &BTreeMap<ClientID, Account<A>> {
+        &self.accounts
+    }
+
+    /// Puts `account` in place for `client_id` without replaying transactions.
+    #[cfg(test)]
+    pub fn insert_account(&mut self, client_id: ClientID, account: Account<A>) {
+        self.accounts.insert(client_id, account);
+    }
+
+    /// Number of accounts locked by a chargeback.
+    pub fn locked_account_count(&self) -> usize {
+        self.accounts.values().filter(|a| a.locked).count()
+    }
+
+    /// Rows accepted without an error that had no effect, e.g. a withdrawal
+    /// without enough funds or a dispute of an unknown transaction.
+    pub fn ignored_count(&self) -> u64 {
+        self.ignored
+    }
+
+    /// Rows parked under [`Config::pending_limit`] whose transaction has not
+    /// arrived yet. They have had no effect so far.
+    pub fn pending_count(&self) -> usize {
+        self.parked
+    }
+
+    /// Every row that referenced [`Config::explain_tx`], in input order.
+    pub fn explained(&self) -> &[ExplainEvent<A>] {
+        &self.explained
+    }
+
+    /// Number of transactions with an open dispute.
+    pub fn disputed_transaction_count(&self) -> usize {
+        self.transactions.values().filter(|t| t.disputed).count()
+    }
+
+    /// `(tx, client, amount)` of every transaction with an open dispute,
+    /// ordered by transaction id.
+    pub fn disputed_transactions(&self) -> Vec<(TransactionID, ClientID, A)> {
+        self.transactions
+            .iter()
+            .filter(|(_, t)| t.disputed)
+            .map(|(&id, t)| (id, t.client_id, t.amount))
+            .collect()
+    }
+
+    /// Sets every account's `held` to the sum of its disputed deposits, e.g.
+    /// after restoring a damaged snapshot. Returns `(client, old_held,
+    /// new_held)` for each account that was corrected, in client order;
+    /// `available` is left alone.
+    pub fn recompute_held(&mut self) -> Vec<(ClientID, A, A)> {
+        let mut disputed: BTreeMap<ClientID, A> = BTreeMap::new();
+        for t in self.transactions.values().filter(|t| t.disputed) {
+            *disputed.entry(t.client_id).or_insert_with(A::zero) += t.amount;
+        }
+        let mut repaired = Vec::new();
+        for (&client_id, account) in &mut self.accounts {
+            let held = disputed.get(&client_id).copied().unwrap_or_else(A::zero);
+            if account.held != held {
+                repaired.push((client_id, account.held, held));
+                account.held = held;
+            }
+        }
+        repaired
+    }
+
+    /// All recorded transactions, ordered by transaction id.
+    pub fn transactions(&self) -> Vec<(TransactionID, &Transaction<A>)> {
+        self.transactions.iter().map(|(id, t)| (*id, t)).collect()
+    }
+
+    /// Recorded transactions of one client, ordered by transaction id.
+    pub fn client_transactions(
+        &self,
+        client_id: ClientID,
+    ) -> Vec<(TransactionID, &Transaction<A>)> {
+        let mut transactions = self.transactions();
+        transactions.retain(|(_, t)| t.client_id == client_id);
+        transactions
+    }
+
+    /// Moves the accounts, transactions and parked rows of `other` into this
+    /// engine. Applied batch ids are united and ignored row counts added up.
+    ///
+    /// Shards must be disjoint: if any client or transaction id is present in
+    /// both engines nothing is merged and the first conflict is returned.
+    ///
+    /// Activity sequence numbers are per engine, so [`Account::last_activity`]
+    /// only orders accounts that came from the same shard. Rows applied after
+    /// the merge are numbered after every merged account.
+    pub fn merge(&mut self, other: Engine<A>) -> Result<(), MergeError> {
+        if let Some(id) = other
+            .accounts
+            .keys()
+            .find(|id| self.accounts.contains_key(id))
+        {
+            return Err(MergeError::OverlappingClient(*id));
+        }
+        if let Some(id) = other
+            .transactions
+            .keys()
+            .find(|id| self.transactions.contains_key(id))
+        {
+            return Err(MergeError::OverlappingTransaction(*id));
+        }
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+        self.applied_batches.extend(other.applied_batches);
+        self.ignored += other.ignored;
+        self.activity = self.activity.max(other.activity);
+        self.explained.extend(other.explained);
+        // parked rows whose transaction came from the other shard are replayed
+        for (transaction_id, parked) in other.pending {
+            self.parked += parked.len();
+            self.pending
+                .entry(transaction_id)
+                .or_default()
+                .extend(parked);
+        }
+        let known: Vec<TransactionID> = self
+            .pending
+            .keys()
+            .filter(|id| self.transactions.contains_key(id))
+            .copied()
+            .collect();
+        for transaction_id in known {
+            self.replay_pending(transaction_id);
+        }
+        Ok(())
+    }
+
+    /// Applies `rows` as one batch, unless a batch with the same id was
+    /// already applied to this engine. Returns false for a replayed batch.
+    ///
+    /// See [`content_batch_id`] for deriving the id from the input bytes.
+    pub fn apply_batch<I: IntoIterator<Item = InputRow>>(
+        &mut self,
+        batch_id: &str,
+        rows: I,
+    ) -> bool {
+        if !self.applied_batches.insert(batch_id.to_string()) {
+            return false;
+        }
+        self.apply_all(rows);
+        true
+    }
+
+    /// Applies `rows` in order, ignoring rejected ones like [`Engine::apply`].
+    pub fn apply_all<I: IntoIterator<Item = InputRow>>(&mut self, rows: I) {
+        for row in rows {
+            self.apply(row);
+        }
+    }
+
+    /// Like [`Engine::apply_all`], for typed commands.
+    pub fn apply_all_commands<I: IntoIterator<Item = Command>>(&mut self, commands: I) {
+        self.apply_all(commands.into_iter().map(InputRow::from));
+    }
+
+    /// Applies a row, silently ignoring it if it is rejected.
+    pub fn apply(&mut self, row: InputRow) {
+        let _ = self.try_apply(row);
+    }
+
+    /// Applies a row, reporting why it was rejected.
+    pub fn try_apply(&mut self, row: InputRow) -> Result<(), ApplyError> {
+        self.try_apply_row(row).map(|_| ())
+    }
+
+    // like try_apply, also telling whether the row changed any state;
+    // filtered, deduplicated, ignored and parked rows did not
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "apply",
+            level = "trace",
+            skip_all,
+            fields(client = row.client_id, tx = row.transaction_id)
+        )
+    )]
+    pub(crate) fn try_apply_row(&mut self, row: InputRow) -> Result<bool, ApplyError> {
+        if self.config.explain_tx != Some(row.transaction_id) {
+            return self.apply_row(row);
+        }
+        let tx = row.transaction_id;
+        let client = row.client_id;
+        let ty = self.normalize_type(&row.transaction_type);
+        // a dispute family row may act on another client's transaction
+        let owner = |engine: &Self| engine.transactions.get(&tx).map_or(client, |t| t.client_id);
+        let balances = |engine: &Self, owner| {
+            let account = engine
+                .accounts
+                .get(&owner)
+                .unwrap_or(&engine.account_template);
+            (account.available, account.held)
+        };
+        // missing accounts are compared from their starting state
+        let before = balances(self, owner(self));
+        let ignored_before = self.ignored;
+        let filtered = matches!(self.admits_client(client), Ok(false));
+        let result = self.apply_row(row);
+        let owner = owner(self);
+        let after = balances(self, owner);
+        let outcome = match &result {
+            Err(e) => ExplainOutcome::Rejected(e.clone()),
+            Ok(_) if filtered || self.ignored > ignored_before => ExplainOutcome::Ignored,
+            Ok(_) => ExplainOutcome::Applied,
+        };
+        self.explained.push(ExplainEvent {
+            ty,
+            client,
+            owner,
+            outcome,
+            available: after.0 - before.0,
+            held: after.1 - before.1,
+        });
+        result
+    }
+
+    fn apply_row(&mut self, row: InputRow) -> Result<bool, ApplyError> {
+        #[cfg(test)]
+        if self.panic_on_tx == Some(row.transaction_id) {
+            panic!("test hook: panic on tx {}", row.transaction_id);
+        }
+        if !self.admits_client(row.client_id)? {
+            return Ok(false);
+        }
+        let transaction_type = self.normalize_type(&row.transaction_type);
+        if self.config.dedup_consecutive_rows {
+            let repeated = self.last_row.as_ref() == Some(&row);
+            self.last_row = Some(row.clone());
+            if repeated
+                && matches!(
+                    transaction_type.as_str(),
+                    "dispute" | "resolve" | "chargeback"
+                )
+            {
+                return Ok(false);
+            }
+        }
+        let currency = self.check_currency(row.client_id, row.currency.as_deref())?;
+        let applied = match transaction_type.as_str() {
+            "open" => {
+                let amount = open_amount(
+                    row.amount.as_deref(),
+                    row.amount_minor.as_deref(),
+                    row.transaction_id,
+                    self.config.input_scale(),
+                    self.config.precision,
+                )?;
+                self.apply_builtin("open", row.client_id, row.transaction_id, amount)?
+            }
+            ty @ ("deposit" | "withdrawal") => {
+                let scale = self.config.input_scale();
+                let amount = amount_field(
+                    row.amount.as_deref(),
+                    row.amount_minor.as_deref(),
+                    row.transaction_id,
+                    scale,
+                    self.config.precision,
+                )?;
+                self.apply_builtin(ty, row.client_id, row.transaction_id, amount)?
+            }
+            // these act on the recorded amount, row.amount is never looked at
+            ty if BUILTIN_TYPES.contains(&ty) => {
+                self.apply_builtin(ty, row.client_id, row.transaction_id, None)?
+            }
+            ty if self.handlers.contains_key(ty) => {
+                // cloned out so the handler can borrow the engine mutably
+                let handler = Arc::clone(&self.handlers[ty]);
+                handler.handle(self, &row)?;
+                true
+            }
+            _ => {
+                return Err(ApplyError::UnknownTransaction {
+                    ty: row.transaction_type.trim().to_string(),
+                });
+            }
+        };
+        if applied {
+            self.record_currency(row.client_id, currency);
+        }
+        Ok(applied)
+    }
+
+    // the row's currency, normalized, unless the account already uses
+    // another one
+    pub(crate) fn check_currency(
+        &self,
+        client_id: ClientID,
+        currency: Option<&str>,
+    ) -> Result<Option<String>, ApplyError> {
+        let Some(currency) = currency
+            .map(|c| c.trim().to_ascii_uppercase())
+            .filter(|c| !c.is_empty())
+        else {
+            return Ok(None);
+        };
+        if let Some(expected) = self.accounts.get(&client_id).and_then(Account::currency)
+            && expected != currency
+        {
+            return Err(ApplyError::CurrencyMismatch {
+                client: client_id,
+                expected: expected.to_string(),
+                found: currency,
+            });
+        }
+        Ok(Some(currency))
+    }
+
+    // after the row was applied, so only an existing account gets one and a
+    // row without effect does not pin the currency
+    pub(crate) fn record_currency(&mut self, client_id: ClientID, currency: Option<String>) {
+        if let Some(account) = self.accounts.get_mut(&client_id)
+            && account.currency.is_none()
+        {
+            account.currency = currency;
+        }
+    }
+
+    // Ok(false) for clients left out by the account filter
+    fn admits_client(&self, client_id: ClientID) -> Result<bool, ApplyError> {
+        if let Some(filter) = &self.config.account_filter
+            && !filter.contains(&client_id)
+        {
+            return Ok(false);
+        }
+        if let Some(max) = self.config.max_client_id
+            && client_id > max
+        {
+            return Err(ApplyError::ClientIdOutOfRange {
+                client: client_id.into(),
+            });
+        }
+        Ok(true)
+    }
+
+    // `ty` is one of BUILTIN_TYPES, `amount` is only used by opens, deposits
+    // and withdrawals and is None when missing or invalid; Ok(true) if the
+    // row took effect
+    fn apply_builtin(
+        &mut self,
+        ty: &str,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Option<A>,
+    ) -> Result<bool, ApplyError> {
+        if self.config.require_open
+            && matches!(ty, "deposit" | "withdrawal")
+            && !self.accounts.contains_key(&client_id)
+        {
+            return Err(ApplyError::AccountNotOpen { client: client_id });
+        }
+        let applied = match ty {
+            "open" => self.open(client_id, amount),
+            "deposit" => {
+                let applied = self.deposit(client_id, transaction_id, amount)?;
+                self.replay_pending(transaction_id);
+                applied
+            }
+            "withdrawal" => {
+                let applied = self.withdrawal(client_id, transaction_id, amount)?;
+                self.replay_pending(transaction_id);
+                applied
+            }
+            "dispute" | "resolve" | "chargeback"
+                if !self.transactions.contains_key(&transaction_id) =>
+            {
+                // a parked row has no effect yet, but is not ignored either
+                if self.park(client_id, transaction_id, ty.to_string()) {
+                    return Ok(false);
+                }
+                false
+            }
+            "dispute" => self.dispute(client_id, transaction_id)?,
+            "resolve" => self.resolve(client_id, transaction_id),
+            "chargeback" => self.chargeback(client_id, transaction_id),
+            "reset" => self.reset(client_id, transaction_id),
+            "void" => self.void(client_id, transaction_id),
+            _ => unreachable!("not a built-in type: {}", ty),
+        };
+        if !applied {
+            self.ignored += 1;
+        }
+        Ok(applied)
+    }
+
+    // keep a dispute family row for later if buffering is enabled and there
+    // is room, false if it was dropped instead
+    fn park(&mut self, client_id: ClientID, transaction_id: TransactionID, ty: String) -> bool {
+        let Some(limit) = self.config.pending_limit else {
+            return false;
+        };
+        if self.parked >= limit {
+            return false;
+        }
+        self.pending
+            .entry(transaction_id)
+            .or_default()
+            .push((client_id, ty));
+        self.parked += 1;
+        true
+    }
+
+    // apply rows parked for a transaction once it has been recorded
+    fn replay_pending(&mut self, transaction_id: TransactionID) {
+        if !self.transactions.contains_key(&transaction_id) {
+            return;
+        }
+        let Some(parked) = self.pending.remove(&transaction_id) else {
+            return;
+        };
+        self.parked -= parked.len();
+        for (client_id, ty) in parked {
+            let applied = match ty.as_str() {
+                // a parked dispute over the limit is dropped like any other
+                "dispute" => self.dispute(client_id, transaction_id).unwrap_or(false),
+                "resolve" => self.resolve(client_id, transaction_id),
+                "chargeback" => self.chargeback(client_id, transaction_id),
+                _ => unreachable!("only dispute family rows are parked"),
+            };
+            if !applied {
+                self.ignored += 1;
+            }
+        }
+    }
+
+    // lowercase and drop all whitespace, then resolve configured aliases
+    pub(crate) fn normalize_type(&self, transaction_type: &str) -> String {
+        let normalized: String = transaction_type
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        match self.config.aliases.get(&normalized) {
+            Some(target) => target.clone(),
+            None => normalized,
+        }
+    }
+
+    fn is_locked(&self, client_id: ClientID) -> bool {
+        self.accounts
+            .get(&client_id)
+            .map(|account| account.locked)
+            .unwrap_or(false)
+    }
+
+    fn get_or_create_account(&mut self, client_id: ClientID) -> &mut Account<A> {
+        self.activity += 1;
+        let account = self
+            .accounts
+            .entry(client_id)
+            .or_insert_with(|| self.account_template.clone());
+        account.last_activity = self.activity;
+        account
+    }
+
+    // creates the account, with `amount` as its starting balance
+    fn open(&mut self, client_id: ClientID, amount: Option<A>) -> bool {
+        if self.accounts.contains_key(&client_id) {
+            return false;
+        }
+        let account = self.get_or_create_account(client_id);
+        if let Some(amount) = amount {
+            account.available += amount;
+            account.net_movement += amount;
+        }
+        true
+    }
+
+    fn deposit(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Option<A>,
+    ) -> Result<bool, ApplyError> {
+        if self.is_locked(client_id) && !self.config.locked_allows_deposit {
+            return Ok(false);
+        }
+        if self.transactions.contains_key(&transaction_id) {
+            return self
+                .check_duplicate_amount(transaction_id, amount)
+                .map(|()| false);
+        }
+
+        let Some(amount) = amount else {
+            return Ok(false);
+        };
+
+        let account = self.get_or_create_account(client_id);
+        account.available += amount;
+        account.net_movement += amount;
+
+        self.transactions.insert(
+            transaction_id,
+            Transaction {
+                client_id,
+                kind: TransactionKind::Deposit,
+                amount,
+                disputed: false,
+                charged_back: false,
+            },
+        );
+        Ok(true)
+    }
+
+    fn withdrawal(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+        amount: Option<A>,
+    ) -> Result<bool, ApplyError> {
+        if self.is_locked(client_id) {
+            return Ok(false);
+        }
+        if self.transactions.contains_key(&transaction_id) {
+            return self
+                .check_duplicate_amount(transaction_id, amount)
+                .map(|()| false);
+        }
+
+        let Some(amount) = amount else {
+            return Ok(false);
+        };
+
+        // check funds before touching the account, a rejected withdrawal
+        // must not create one for an unseen client
+        let available = self
+            .accounts
+            .get(&client_id)
+            .unwrap_or(&self.account_template)
+            .available;
+        // explicit requirement from the spec, unless an overdraft covers it
+        let overdraft = self
+            .config
+            .overdraft_limit
+            .map_or_else(A::zero, A::from_currency);
+        if available + overdraft < amount {
+            return Ok(false);
+        }
+        let account = self.get_or_create_account(client_id);
+        account.available -= amount;
+        account.net_movement -= amount;
+
+        self.transactions.insert(
+            transaction_id,
+            Transaction {
+                client_id,
+                kind: TransactionKind::Withdrawal,
+                amount,
+                disputed: false,
+                charged_back: false,
+            },
+        );
+        Ok(true)
+    }
+
+    // a repeated tx id is dropped, unless checking finds a different amount
+    fn check_duplicate_amount(
+        &self,
+        transaction_id: TransactionID,
+        amount: Option<A>,
+    ) -> Result<(), ApplyError> {
+        if !self.config.check_duplicate_amounts {
+            return Ok(());
+        }
+        let original = self.transactions[&transaction_id].amount;
+        match amount {
+            Some(amount) if amount != original => {
+                Err(ApplyError::DuplicateAmountMismatch { tx: transaction_id })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // `client_id` filed the dispute, which is the owner of the transaction
+    // unless it is one of the configured dispute agents
+    fn dispute(
+        &mut self,
+        client_id: ClientID,
+        transaction_id: TransactionID,
+    ) -> Result<bool, ApplyError> {
+        let (owner, amount) = {
+            let t = match self.transactions.get(&transaction_id) {
+                Some(t) => t,
+                None => return Ok(false),
+            };
+            let authorized =
+                t.client_id == client_id || self.config.dispute_agents.contains(&client_id);
+            // check if client mismatch, not a deposit, or already disputed
+            if !authorized || t.kind != TransactionKind::Deposit || t.disputed {
+                return Ok(false);
+            }
+            (t.client_id, t.amount)
+        };
+        let client_id = owner;
+        if self.is_locked(client_id) && !self.config.allow_dispute_when_locked {
+            return Ok(false);
+        }
+        if let Some(max) = self.config.max_disputes
+            && self
+                .accounts
+                .get(&client_id)
+                .is_some_and(|account| account.disputes >= max)
+        {
+            return Err(ApplyError::DisputeLimitExceeded { client: client_id });
+        }
+
+        let mode = self.config.dispute_mode;
+        if self.config.dispute_requires_funds
+            && self
+                .accounts
+                .get(&client_id)
+                .is_none_or(|account| account.available < amount)
+        {
+            return Ok(false);
+        }
+        let account = self.get_or_create_account(client_id);
+        if mode == DisputeMode::Debit {
+            account.available -= amount;
+        }
+        account.held += amount;
+        account.disputes += 1;
+
+        if let Some(t) = self.transactions.get_mut(&transaction_id) {
+            t.disputed = true;
+        }
+        Ok(true)
+    }
+
+    fn resolve(&mut self, client_id: ClientID, transaction_id: TransactionID) -> bool {
+        if self.is_locked(client_id) && !self.config.allow_dispute_when_locked {
+            return false;
+        }
+
+        let amount = {
+            let t = match self.transactions.get(&transaction_id) {
+                Some(t) => t,
+                None => return false,
+            };
+            // check if client mismatch, not a deposit, or transaction not disputed
+            if t.client_id != client_id || t.kind != TransactionKind::Deposit || !t.disputed {
+                return false;
+            }
+            t.amount
+        };
+
+        let mode = self.config.dispute_mode;
+        let account = self.get_or_create_account(client_id);
+        if account.held < amount {
+            return false;
+        }
+        account.held -= amount;
+        if mode == DisputeMode::Debit {
+            account.available += amount;
+        }
+
+        if let Some(t) = self.transactions.get_mut(&transaction_id) {
+            t.disputed = false;
+        }
+        true
+    }
+
+    fn chargeback(&mut self, client_id: ClientID, transaction_id: TransactionID) -> bool {
+        if self.is_locked(client_id) {
+            return false;
+        }
+        let amount = {
+            let t = match self.transactions.get(&transaction_id) {
+                Some(t) => t,
+                None => return false,
+            };
+            // check if client mismatch, not a deposit, or transaction not disputed
+            if t.client_id != client_id || t.kind != TransactionKind::Deposit || !t.disputed {
+                return false;
+            }
+            t.amount
+        };
+
+        let mode = self.config.dispute_mode;
+        let chargeback_mode = self.config.chargeback_mode;
+        let account = self.get_or_create_account(client_id);
+        if account.held < amount {
+            return false;
+        }
+        account.held -= amount;
+        match (chargeback_mode, mode) {
+            // the hold never left available, the charged back funds do now
+            (ChargebackMode::Reverse, DisputeMode::HoldOnly) => account.available -= amount,
+            // the dispute took the funds out of available, pay them back
+            (ChargebackMode::Refund, DisputeMode::Debit) => account.available += amount,
+            _ => {}
+        }
+        account.locked = true;
+
+        if let Some(t) = self.transactions.get_mut(&transaction_id) {
+            t.disputed = false;
+            t.charged_back = true;
+        }
+        true
+    }
+
+    // undo a deposit or withdrawal entered by mistake and forget it, so its
+    // id can be used again
+    fn void(&mut self, client_id: ClientID, transaction_id: TransactionID) -> bool {
+        if self.is_locked(client_id) {
+            return false;
+        }
+        let (kind, amount) = {
+            let t = match self.transactions.get(&transaction_id) {
+                Some(t) => t,
+                None => return false,
+            };
+            // check if client mismatch, already under dispute or charged back
+            if t.client_id != client_id || t.disputed || t.charged_back {
+                return false;
+            }
+            (t.kind, t.amount)
+        };
+        let Some(account) = self.accounts.get(&client_id) else {
+            return false;
+        };
+        match kind {
+            // funds that were already spent cannot be taken back
+            TransactionKind::Deposit if account.available < amount => return false,
+            TransactionKind::Deposit | TransactionKind::Withdrawal => {}
+            TransactionKind::Reset => return false,
+        }
+        let account = self.get_or_create_account(client_id);
+        if kind == TransactionKind::Deposit {
+            account.available -= amount;
+            account.net_movement -= amount;
+        } else {
+            account.available += amount;
+            account.net_movement += amount;
+        }
+        self.transactions.remove(&transaction_id);
+        true
+    }
+
+    fn reset(&mut self, client_id: ClientID, transaction_id: TransactionID) -> bool {
+        if !self.config.allow_admin_ops {
+            return false;
+        }
+        if self.transactions.contains_key(&transaction_id) {
+            return false;
+        }
+        // locked accounts are not skipped here, unlocking is part of the reset
+        if !self.accounts.contains_key(&client_id) {
+            return false;
+        }
+        let account = self.get_or_create_account(client_id);
+        let wiped = account.total();
+        // the dispute count is lifetime, a reset must not lift the cap
+        *account = Account {
+            net_movement: account.net_movement,
+            last_activity: account.last_activity,
+            disputes: account.disputes,
+            currency: account.currency.take(),
+            ..Account::default()
+        };
+        // held went to zero, so nothing of this client is under dispute anymore
+        for transaction in self.transactions.values_mut() {
+            if transaction.client_id == client_id {
+                transaction.disputed = false;
+            }
+        }
+
+        // keep an audit record of the reset, amount is the total that was wiped
+        self.transactions.insert(
+            transaction_id,
+            Transaction {
+                client_id,
+                kind: TransactionKind::Reset,
+                amount: wiped,
+                disputed: false,
+                charged_back: false,
+            },
+        );
+        true
+    }
+}
+
+// the amount of a row, from `amount` or converted from `amount_minor`; None
+// when missing or invalid, including an unparsable minor amount
+pub(crate) fn amount_field<A: Amount>(
+    amount: Option<&str>,
+    amount_minor: Option<&str>,
+    transaction_id: TransactionID,
+    scale: u32,
+    precision: PrecisionPolicy,
+) -> Result<Option<A>, ApplyError> {
+    match (amount, amount_minor) {
+        (Some(_), Some(_)) => Err(ApplyError::InvalidAmount { tx: transaction_id }),
+        (None, Some(minor)) => Ok(minor
+            .parse::<i64>()
+            .ok()
+            .and_then(|minor| Decimal::try_new(minor, scale).ok())
+            .and_then(|amount| parse_input_amount(Some(&amount.to_string()), scale, precision))),
+        (amount, None) => Ok(parse_input_amount(amount, scale, precision)),
+    }
+}
+
+// an open row may leave its starting balance out, but a given one must be
+// valid, unlike deposits the row is rejected otherwise
+pub(crate) fn open_amount<A: Amount>(
+    amount: Option<&str>,
+    amount_minor: Option<&str>,
+    transaction_id: TransactionID,
+    scale: u32,
+    precision: PrecisionPolicy,
+) -> Result<Option<A>, ApplyError> {
+    let parsed = amount_field(amount, amount_minor, transaction_id, scale, precision)?;
+    let given = |value: Option<&str>| value.is_some_and(|v| !v.trim().is_empty());
+    if parsed.is_none() && (given(amount) || given(amount_minor)) {
+        return Err(ApplyError::InvalidAmount { tx: transaction_id });
+    }
+    Ok(parsed)
+}
+
+// convert the raw field to a positive amount, None if missing or invalid
+pub(crate) fn parse_input_amount<A: Amount>(
+    amount: Option<&str>,
+    scale: u32,
+    precision: PrecisionPolicy,
+) -> Option<A> {
+    let amount = A::parse_with_policy(amount?, scale, precision).ok()?;
+    if amount <= A::zero() {
+        return None;
+    }
+    Some(amount)
+}
+
+pub fn parse_amount(amount: &str) -> Result<Currency, String> {
+    parse_amount_with_scale(amount, DEFAULT_SCALE)
+}
+
+/// Like [`parse_amount`], allowing up to `scale` decimal places.
+pub fn parse_amount_with_scale(amount: &str, scale: u32) -> Result<Currency, String> {
+    parse_amount_with_policy(amount, scale, PrecisionPolicy::Reject)
+}
+
+/// Like [`parse_amount_with_scale`], bringing amounts with more decimal
+/// places to `scale` as `policy` says.
+pub fn parse_amount_with_policy(
+    amount: &str,
+    scale: u32,
+    policy: PrecisionPolicy,
+) -> Result<Currency, String> {
+    let t = amount.trim();
+    if t.is_empty() {
+        return Err("empty amount".to_string());
+    }
+    let mut d = Decimal::from_str(t).map_err(|_| "bad amount".to_string())?;
+
+    // reject zero or negative amounts
+    if d <= Decimal::ZERO {
+        return Err("amount must be positive".to_string());
+    }
+
+    // Enforce max `scale` decimal places (4 by default).
+    // If input has more, we fail rather than silently round, to avoid spec ambiguity,
+    // unless a rounding policy was chosen explicitly.
+    if d.scale() > scale {
+        d = match policy {
+            PrecisionPolicy::Reject => return Err("too many decimal places".to_string()),
+            PrecisionPolicy::Round => {
+                d.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero)
+            }
+            PrecisionPolicy::Truncate => d.trunc_with_scale(scale),
+        };
+        // e.g. 0.00004 at scale 4, must not become a zero-value transaction
+        if d.is_zero() {
+            return Err("amount must be positive".to_string());
+        }
+    }
+
+    // Normalize to exactly `scale` dp for stable output.
+    d = d.round_dp(scale);
+    Ok(d)
+}
+
+/// Batch id derived from the raw input, so replaying the same file is detected.
+///
+/// Uses SHA-256, so two different inputs do not end up with the same id and
+/// one of them skipped as a replay.
+pub fn content_batch_id(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Builds the CSV reader used for transaction input.
+pub fn csv_reader<R: io::Read>(reader: R) -> csv::Reader<R> {
+    csv_reader_with_delimiter(reader, b',')
+}
+
+/// Like [`csv_reader`], splitting fields on `delimiter`.
+pub fn csv_reader_with_delimiter<R: io::Read>(reader: R, delimiter: u8) -> csv::Reader<R> {
+    reader_builder(delimiter).from_reader(reader)
+}
+
+// the reader over raw input shared by processing and validation: comment
+// lines, the quote guard, the field length limit and the sniffed dialect
+pub(crate) fn input_reader<R: io::BufRead>(
+    mut reader: R,
+    config: &Config,
+) -> csv::Reader<QuoteGuard<io::Chain<io::Cursor<Vec<u8>>, R>>> {
+    let max_field_len = config.max_field_len.unwrap_or(DEFAULT_MAX_FIELD_LEN);
+    let mut delimiter = b',';
+    let mut header = Vec::new();
+    if config.auto_dialect {
+        // the whole header line, however it is split across reads, is put
+        // back in front of the input once the delimiter is known; a read
+        // error surfaces again on the next read
+        let limit = max_field_len.saturating_mul(MAX_LINE_FIELDS);
+        let mut first = io::Read::take(&mut reader, limit as u64);
+        let _ = io::BufRead::read_until(&mut first, b'\n', &mut header);
+        delimiter = sniff_delimiter(&header).unwrap_or(delimiter);
+    }
+    let reader = io::Read::chain(io::Cursor::new(header), reader);
+    let reader = QuoteGuard::new(reader, config.comment, delimiter, max_field_len);
+    reader_builder(delimiter)
+        .comment(config.comment)
+        .from_reader(reader)
+}
+
+fn reader_builder(delimiter: u8) -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .delimiter(delimiter)
+        .trim(csv::Trim::All)
+        .flexible(true);
+    builder
+}
+
+/// Counters collected while processing an input.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Records read from the input, excluding the header.
+    pub rows: u64,
+    /// Records that could not be parsed into a row.
+    pub malformed: u64,
+    /// Rows the engine rejected with an [`ApplyError`].
+    pub rejected: u64,
+    /// Rows the engine accepted without effect, see [`Engine::ignored_count`].
+    pub ignored: u64,
+    /// Rows still parked when the input ended, see [`Engine::pending_count`].
+    pub pending: u64,
+    /// Row count per normalized transaction type, unknown types included.
+    pub types: HashMap<String, u64>,
+    /// Time spent reading and deserializing records, zero unless
+    /// [`Config::timings`] is set.
+    pub parse_time: Duration,
+    /// Time spent applying rows to the engine, zero unless
+    /// [`Config::timings`] is set.
+    pub apply_time: Duration,
+}
+
+/// Reads every row from `reader` and applies it to `engine`.
+///
+/// Rejected rows are skipped unless the engine is in strict mode, in which
+/// case the first rejection is returned. The reader is used as given, so its
+/// buffer size, e.g. of an [`io::BufReader`], is the size of each read.
+pub fn process_reader<A: Amount, R: io::BufRead>(
+    reader: R,
+    engine: &mut Engine<A>,
+) -> Result<Stats, ApplyError> {
+    if engine.config.fast_path && engine.fast_path_applies() {
+        return fast::process_records(reader, engine);
+    }
+    process_reader_streaming(reader, engine, None, |_, _| {})
+}
+
+/// [`process_reader`] over CSV already in memory, e.g. a fixed test input.
+pub fn process_bytes<A: Amount>(data: &[u8], engine: &mut Engine<A>) -> Result<Stats, ApplyError> {
+    process_reader(data, engine)
+}
+
+/// Like [`process_reader`], calling `on_applied` with the client and its
+/// updated account after every row that took effect. Rows the engine
+/// accepted without changing anything, such as a withdrawal over the
+/// available funds, are not reported.
+///
+/// With a `limiter` the callback is delayed to respect its per-client rate;
+/// the computed balances are the same either way.
+pub fn process_reader_streaming<A, R, F>(
+    reader: R,
+    engine: &mut Engine<A>,
+    limiter: Option<&mut RateLimiter>,
+    on_applied: F,
+) -> Result<Stats, ApplyError>
+where
+    A: Amount,
+    R: io::BufRead,
+    F: FnMut(ClientID, &Account<A>),
+{
+    process_records(reader, engine, limiter, on_applied, None, |_| {})
+}
+
+/// Like [`process_reader`], calling `on_checkpoint` with the engine after
+/// every `every` input records. The state after the last record is not
+/// passed to the callback, the caller already has it once this returns.
+pub fn process_reader_checkpointed<A, R, C>(
+    reader: R,
+    engine: &mut Engine<A>,
+    every: u64,
+    on_checkpoint: C,
+) -> Result<Stats, ApplyError>
+where
+    A: Amount,
+    R: io::BufRead,
+    C: FnMut(&Engine<A>),
+{
+    process_records(reader, engine, None, |_, _| {}, Some(every), on_checkpoint)
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "process", skip_all, fields(rows = tracing::field::Empty))
+)]
+fn process_records<A, R, F, C>(
+    reader: R,
+    engine: &mut Engine<A>,
+    mut limiter: Option<&mut RateLimiter>,
+    mut on_applied: F,
+    checkpoint_every: Option<u64>,
+    mut on_checkpoint: C,
+) -> Result<Stats, ApplyError>
+where
+    A: Amount,
+    R: io::BufRead,
+    F: FnMut(ClientID, &Account<A>),
+    C: FnMut(&Engine<A>),
+{
+    let mut stats = Stats::default();
+    let max_field_len = engine.config.max_field_len.unwrap_or(DEFAULT_MAX_FIELD_LEN);
+    let mut reader = input_reader(reader, &engine.config);
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(_) => return Ok(stats),
+    };
+    // serde would silently pick one of the columns
+    if let Some(name) = duplicate_header(&headers) {
+        return Err(ApplyError::DuplicateHeader {
+            name: name.to_string(),
+        });
+    }
+    let schema_version = match engine.config.require_schema_version {
+        Some(expected) => match headers.iter().position(|h| h == "schema_version") {
+            Some(index) => Some((expected, index)),
+            None => {
+                return Err(ApplyError::SchemaVersionMismatch {
+                    expected,
+                    found: None,
+                });
+            }
+        },
+        None => None,
+    };
+    let timings = engine.config.timings;
+    let ignored_before = engine.ignored;
+    let mut records = reader.records();
+    // looked ahead only for detect_truncation, so streaming output is not
+    // held back waiting for the next line
+    let mut peeked = None;
+    loop {
+        let read_started = timings.then(Instant::now);
+        let Some(record) = peeked.take().or_else(|| records.next()) else {
+            break;
+        };
+        stats.parse_time += elapsed_since(read_started);
+        // checked before counting, so it fires once the previous record is done
+        if let Some(every) = checkpoint_every
+            && stats.rows > 0
+            && stats.rows.is_multiple_of(every)
+        {
+            on_checkpoint(engine);
+        }
+        stats.rows += 1;
+        if engine.config.detect_truncation {
+            peeked = records.next();
+            let terminated = records.reader().get_ref().ends_with_newline();
+            if peeked.is_none() && is_truncated(terminated, &headers, record.as_ref().ok()) {
+                return Err(ApplyError::TruncatedInput { row: stats.rows });
+            }
+        }
+        // malformed rows are skipped
+        let Ok(record) = record else {
+            stats.malformed += 1;
+            continue;
+        };
+        // a version mismatch means the whole file is in an unknown format
+        if let Some((expected, index)) = schema_version {
+            let found = record.get(index).unwrap_or_default();
+            if found.parse() != Ok(expected) {
+                return Err(ApplyError::SchemaVersionMismatch {
+                    expected,
+                    found: Some(found.to_string()),
+                });
+            }
+        }
+        let mut client = None;
+        let mut transaction_id = None;
+        // the reader already cut an oversized field one byte past the limit,
+        // this turns it into a rejection before anything is deserialized
+        let result = if let Some(len) = record.iter().map(str::len).find(|&len| len > max_field_len)
+        {
+            Err(ApplyError::FieldTooLong { len })
+        } else {
+            let parse_started = timings.then(Instant::now);
+            let parsed = record.deserialize::<InputRow>(Some(&headers));
+            stats.parse_time += elapsed_since(parse_started);
+            match parsed {
+                Ok(row) => {
+                    let ty = engine.normalize_type(&row.transaction_type);
+                    *stats.types.entry(ty).or_default() += 1;
+                    match engine.config.in_time_window(row.timestamp.as_deref()) {
+                        Some(true) => {
+                            client = Some(row.client_id);
+                            transaction_id = Some(row.transaction_id);
+                            let apply_started = timings.then(Instant::now);
+                            let result = if engine.config.catch_panics {
+                                let tx = row.transaction_id;
+                                panic::catch_unwind(AssertUnwindSafe(|| engine.try_apply_row(row)))
+                                    .unwrap_or(Err(ApplyError::Panicked { tx }))
+                            } else {
+                                engine.try_apply_row(row)
+                            };
+                            stats.apply_time += elapsed_since(apply_started);
+                            result
+                        }
+                        Some(false) => continue,
+                        None => {
+                            stats.malformed += 1;
+                            continue;
+                        }
+                    }
+                }
+                Err(_) => match client_id_overflow(&headers, &record) {
+                    Some(client) => Err(ApplyError::ClientIdOutOfRange { client }),
+                    None if engine.config.strict
+                        && let Some(field) = missing_field(&headers, &record) =>
+                    {
+                        Err(ApplyError::MissingField { field })
+                    }
+                    None => {
+                        stats.malformed += 1;
+                        continue;
+                    }
+                },
+            }
+        };
+        match result {
+            Ok(false) => {}
+            Ok(true) => {
+                if let Some(client) = client
+                    && let Some(account) = engine.accounts.get(&client)
+                {
+                    if let Some(limiter) = limiter.as_deref_mut() {
+                        limiter.wait(client);
+                    }
+                    on_applied(client, account);
+                }
+            }
+            Err(e) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(error = %e, "row rejected");
+                if engine.config.strict {
+                    return Err(e);
+                }
+                stats.rejected += 1;
+            }
+        }
+        if transaction_id.is_some() && transaction_id == engine.config.as_of_tx {
+            break;
+        }
+    }
+    stats.ignored = engine.ignored - ignored_before;
+    stats.pending = engine.pending_count() as u64;
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("rows", stats.rows);
+    Ok(stats)
+}
+
+// time since `started`, zero when timings are off
+pub(crate) fn elapsed_since(started: Option<Instant>) -> Duration {
+    started.map_or(Duration::ZERO, |started| started.elapsed())
+}
+
+// a last record without a line break that is unreadable or unparsable
+// suggests the input was cut off mid-row; the field count says nothing, as
+// dispute rows and optional columns may be left out
+fn is_truncated(
+    terminated: bool,
+    headers: &csv::StringRecord,
+    record: Option<&csv::StringRecord>,
+) -> bool {
+    !terminated && record.is_none_or(|r| r.deserialize::<InputRow>(Some(headers)).is_err())
+}
+
+// tried in order by auto_dialect, comma first so it wins when it works
+const DIALECT_DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+// the first delimiter that splits `header` into the expected columns
+fn sniff_delimiter(header: &[u8]) -> Option<u8> {
+    let header = String::from_utf8_lossy(header);
+    DIALECT_DELIMITERS.into_iter().find(|&delimiter| {
+        let columns: Vec<&str> = header.split(char::from(delimiter)).map(str::trim).collect();
+        ["type", "client", "tx"]
+            .iter()
+            .all(|column| columns.contains(column))
+    })
+}
+
+/// First header name that was already seen earlier in the header.
+pub(crate) fn duplicate_header(headers: &csv::StringRecord) -> Option<&str> {
+    let mut seen = HashSet::new();
+    headers.iter().find(|&name| !seen.insert(name))
+}
+
+// a client id that is a valid number but does not fit in ClientID
+pub(crate) fn client_id_overflow(
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+) -> Option<u64> {
+    let index = headers.iter().position(|h| h == "client")?;
+    let client: u64 = record.get(index)?.parse().ok()?;
+    (client > ClientID::MAX.into()).then_some(client)
+}
+
+/// First of the id columns that is present but blank in `record`.
+pub(crate) fn missing_field(
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+) -> Option<&'static str> {
+    ["client", "tx"].into_iter().find(|&field| {
+        headers
+            .iter()
+            .position(|h| h == field)
+            .and_then(|index| record.get(index))
+            .is_some_and(|value| value.trim().is_empty())
+    })
+}
+
+#[cfg(test)]
+mod tests;
@@ -1,309 +1,825 @@
-use rust_decimal::Decimal;
-use rust_decimal::prelude::*;
-use serde::Deserialize;
-use std::collections::HashMap;
+use chrono::{DateTime, FixedOffset};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs::File;
-use std::io;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Write};
+use std::time::{Duration, Instant};
+use transaction_processing::{
+    ChargebackMode, ClientID, Config, CsvSink, Currency, DEFAULT_SCALE, DisputeMode, Engine,
+    ExplainEvent, ExplainOutcome, LEGACY_TYPE_ALIASES, OutputRow, OutputSink, PrecisionPolicy,
+    Stats, TableSink, ValidationReport, process_reader, process_reader_checkpointed,
+    process_reader_streaming, rows_checksum, validate_reader, write_held_detail, write_to_sink,
+    write_transactions,
+};
+
+const DEFAULT_READ_BUFFER_BYTES: usize = 64 * 1024;
+// exit code for any error that stops processing
+const ERROR_EXIT_CODE: i32 = 1;
+// exit code for --fail-on-locked when any account ends up locked
+const LOCKED_EXIT_CODE: i32 = 3;
+// exit code for the validate subcommand when any row is invalid
+const INVALID_INPUT_EXIT_CODE: i32 = 4;
+// invalid rows listed by the validate subcommand, the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 10;
+// exit code for --expect when the report differs from the expected file
+const EXPECT_MISMATCH_EXIT_CODE: i32 = 5;
+// exit code for --fail-on-reject when any row was not applied
+const REJECTED_EXIT_CODE: i32 = 6;
+// differing lines listed by --expect, the rest are only counted
+const MAX_REPORTED_DIFFS: usize = 10;
+// most decimal places rust_decimal can represent
+const MAX_SCALE: u32 = 28;
 
-type ClientID = u16;
-type TransactionID = u64;
-type Currency = Decimal;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Csv,
+    Table,
+    // disputed transactions making up each client's held balance
+    HeldDetail,
+}
 
-#[derive(Debug, Deserialize)]
-struct InputRow {
-    #[serde(rename = "type")]
-    transaction_type: String,
-    #[serde(rename = "client")]
-    client_id: ClientID,
-    #[serde(rename = "tx")]
-    transaction_id: TransactionID,
-    #[serde(default)]
-    amount: Option<String>,
+#[derive(Debug, Default)]
+struct Options {
+    path: Option<String>,
+    allow_admin_ops: bool,
+    dedup_consecutive_rows: bool,
+    strict: bool,
+    aliases: HashMap<String, String>,
+    tail: Option<usize>,
+    max_client_id: Option<ClientID>,
+    balance_views: bool,
+    read_buffer_bytes: Option<usize>,
+    stats: bool,
+    fail_on_locked: bool,
+    grand_total: bool,
+    group_output_by_currency: bool,
+    allow_dispute_when_locked: bool,
+    dispute_mode: DisputeMode,
+    chargeback_mode: ChargebackMode,
+    since: Option<DateTime<FixedOffset>>,
+    until: Option<DateTime<FixedOffset>>,
+    exclude_untimestamped: bool,
+    as_of_tx: Option<u64>,
+    pending_limit: Option<usize>,
+    require_schema_version: Option<u32>,
+    // intermediate reports go where the final one does, stdout or --out
+    checkpoint_every: Option<u64>,
+    input_scale: Option<u32>,
+    output_scale: Option<u32>,
+    check_duplicate_amounts: bool,
+    quiet: bool,
+    account_filter: Option<HashSet<ClientID>>,
+    catch_panics: bool,
+    locked_allows_deposit: bool,
+    auto_dialect: bool,
+    net_movement: bool,
+    max_field_len: Option<usize>,
+    top: Option<usize>,
+    dump_transactions: Option<String>,
+    detect_truncation: bool,
+    comment: Option<u8>,
+    dispute_requires_funds: bool,
+    expect: Option<String>,
+    interactive: bool,
+    fast_path: bool,
+    out: Option<String>,
+    emit_empty_on_no_clients: bool,
+    timings: bool,
+    output: Option<OutputFormat>,
+    fail_on_reject: bool,
+    require_open: bool,
+    dump_state: Option<String>,
+    dispute_agents: HashSet<ClientID>,
+    checksum: bool,
+    overdraft_limit: Option<Currency>,
+    explain: Option<u64>,
+    max_disputes: Option<u64>,
+    emit_config_header: bool,
+    precision: PrecisionPolicy,
 }
 
-#[derive(Debug, Default, Clone)]
-struct Account {
-    available: Currency,
-    held: Currency,
-    locked: bool,
+fn option_value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("option {} requires a value", flag))
 }
 
-impl Account {
-    fn total(&self) -> Currency {
-        self.available + self.held
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--allow-admin-ops" => options.allow_admin_ops = true,
+            "--dedup-consecutive" => options.dedup_consecutive_rows = true,
+            "--strict" => options.strict = true,
+            "--balance-views" => options.balance_views = true,
+            "--stats" => options.stats = true,
+            "--timings" => options.timings = true,
+            "--quiet" => options.quiet = true,
+            "--catch-panics" => options.catch_panics = true,
+            "--locked-allows-deposit" => options.locked_allows_deposit = true,
+            "--auto-dialect" => options.auto_dialect = true,
+            "--detect-truncation" => options.detect_truncation = true,
+            "--net-movement" => options.net_movement = true,
+            "--interactive" => options.interactive = true,
+            "--fast-path" => options.fast_path = true,
+            "--emit-empty-on-no-clients" => options.emit_empty_on_no_clients = true,
+            "--fail-on-locked" => options.fail_on_locked = true,
+            "--fail-on-reject" => options.fail_on_reject = true,
+            "--require-open" => options.require_open = true,
+            "--checksum" => options.checksum = true,
+            "--emit-config-header" => options.emit_config_header = true,
+            "--grand-total" => options.grand_total = true,
+            "--group-output-by-currency" => options.group_output_by_currency = true,
+            "--allow-dispute-when-locked" => options.allow_dispute_when_locked = true,
+            "--dispute-requires-funds" => options.dispute_requires_funds = true,
+            "--normalize-types" => {
+                // explicit --alias options win, whatever their position
+                for (alias, target) in LEGACY_TYPE_ALIASES {
+                    options
+                        .aliases
+                        .entry(alias.to_string())
+                        .or_insert_with(|| target.to_string());
+                }
+            }
+            "--alias" => {
+                let value = option_value(&mut args, &arg)?;
+                let (alias, target) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("invalid alias {}, expected name=type", value))?;
+                options.aliases.insert(
+                    alias.trim().to_ascii_lowercase(),
+                    target.trim().to_ascii_lowercase(),
+                );
+            }
+            "--account-filter" => {
+                let value = option_value(&mut args, &arg)?;
+                let clients = value
+                    .split(',')
+                    .map(|client| client.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("invalid value for --account-filter: {}", value))?;
+                options.account_filter = Some(clients);
+            }
+            "--dispute-agents" => {
+                let value = option_value(&mut args, &arg)?;
+                options.dispute_agents = value
+                    .split(',')
+                    .map(|client| client.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("invalid value for --dispute-agents: {}", value))?;
+            }
+            "--max-field-len" => {
+                let value = option_value(&mut args, &arg)?;
+                let len = value
+                    .parse()
+                    .ok()
+                    .filter(|&n: &usize| n > 0)
+                    .ok_or_else(|| format!("invalid value for --max-field-len: {}", value))?;
+                options.max_field_len = Some(len);
+            }
+            "--tail" => {
+                let value = option_value(&mut args, &arg)?;
+                let n = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for --tail: {}", value))?;
+                options.tail = Some(n);
+            }
+            "--expect" => options.expect = Some(option_value(&mut args, &arg)?),
+            "--out" => options.out = Some(option_value(&mut args, &arg)?),
+            "--dump-state" => options.dump_state = Some(option_value(&mut args, &arg)?),
+            "--dump-transactions" => {
+                options.dump_transactions = Some(option_value(&mut args, &arg)?);
+            }
+            "--comment" => {
+                let value = option_value(&mut args, &arg)?;
+                let comment = match value.as_bytes() {
+                    &[byte] if byte.is_ascii() => byte,
+                    _ => return Err(format!("invalid value for --comment: {}", value)),
+                };
+                options.comment = Some(comment);
+            }
+            "--top" => {
+                let value = option_value(&mut args, &arg)?;
+                let n = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for --top: {}", value))?;
+                options.top = Some(n);
+            }
+            "--max-client-id" => {
+                let value = option_value(&mut args, &arg)?;
+                let max = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for --max-client-id: {}", value))?;
+                options.max_client_id = Some(max);
+            }
+            "--read-buffer-bytes" => {
+                let value = option_value(&mut args, &arg)?;
+                let bytes = value
+                    .parse()
+                    .ok()
+                    .filter(|&n: &usize| n > 0)
+                    .ok_or_else(|| format!("invalid value for --read-buffer-bytes: {}", value))?;
+                options.read_buffer_bytes = Some(bytes);
+            }
+            "--dispute-mode" => {
+                let value = option_value(&mut args, &arg)?;
+                options.dispute_mode = match value.as_str() {
+                    "debit" => DisputeMode::Debit,
+                    "hold-only" => DisputeMode::HoldOnly,
+                    _ => return Err(format!("invalid value for --dispute-mode: {}", value)),
+                };
+            }
+            "--output" => {
+                let value = option_value(&mut args, &arg)?;
+                options.output = Some(match value.as_str() {
+                    "csv" => OutputFormat::Csv,
+                    "table" => OutputFormat::Table,
+                    "held-detail" => OutputFormat::HeldDetail,
+                    _ => return Err(format!("invalid value for --output: {}", value)),
+                });
+            }
+            "--chargeback-mode" => {
+                let value = option_value(&mut args, &arg)?;
+                options.chargeback_mode = match value.as_str() {
+                    "reverse" => ChargebackMode::Reverse,
+                    "refund" => ChargebackMode::Refund,
+                    _ => return Err(format!("invalid value for --chargeback-mode: {}", value)),
+                };
+            }
+            "--precision" => {
+                let value = option_value(&mut args, &arg)?;
+                options.precision = match value.as_str() {
+                    "reject" => PrecisionPolicy::Reject,
+                    "round" => PrecisionPolicy::Round,
+                    "truncate" => PrecisionPolicy::Truncate,
+                    _ => return Err(format!("invalid value for --precision: {}", value)),
+                };
+            }
+            "--since" | "--until" => {
+                let value = option_value(&mut args, &arg)?;
+                let bound = DateTime::parse_from_rfc3339(&value)
+                    .map_err(|e| format!("invalid RFC 3339 time for {}: {}", arg, e))?;
+                if arg == "--since" {
+                    options.since = Some(bound);
+                } else {
+                    options.until = Some(bound);
+                }
+            }
+            "--exclude-untimestamped" => options.exclude_untimestamped = true,
+            "--check-duplicate-amounts" => options.check_duplicate_amounts = true,
+            "--as-of-tx" => {
+                let value = option_value(&mut args, &arg)?;
+                let tx = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for --as-of-tx: {}", value))?;
+                options.as_of_tx = Some(tx);
+            }
+            "--overdraft-limit" => {
+                let value = option_value(&mut args, &arg)?;
+                let limit = value
+                    .parse()
+                    .ok()
+                    .filter(|limit: &Currency| !limit.is_sign_negative())
+                    .ok_or_else(|| format!("invalid value for --overdraft-limit: {}", value))?;
+                options.overdraft_limit = Some(limit);
+            }
+            "--explain" => {
+                let value = option_value(&mut args, &arg)?;
+                let tx = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for --explain: {}", value))?;
+                options.explain = Some(tx);
+            }
+            "--max-disputes" => {
+                let value = option_value(&mut args, &arg)?;
+                let max = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for --max-disputes: {}", value))?;
+                options.max_disputes = Some(max);
+            }
+            "--pending-limit" => {
+                let value = option_value(&mut args, &arg)?;
+                let limit = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for --pending-limit: {}", value))?;
+                options.pending_limit = Some(limit);
+            }
+            "--require-schema-version" => {
+                let value = option_value(&mut args, &arg)?;
+                let version = value.parse().map_err(|_| {
+                    format!("invalid value for --require-schema-version: {}", value)
+                })?;
+                options.require_schema_version = Some(version);
+            }
+            "--checkpoint-every" => {
+                let value = option_value(&mut args, &arg)?;
+                let every =
+                    value.parse().ok().filter(|&n: &u64| n > 0).ok_or_else(|| {
+                        format!("invalid value for --checkpoint-every: {}", value)
+                    })?;
+                options.checkpoint_every = Some(every);
+            }
+            "--input-scale" | "--output-scale" => {
+                let value = option_value(&mut args, &arg)?;
+                let scale = value
+                    .parse()
+                    .ok()
+                    .filter(|&n: &u32| n <= MAX_SCALE)
+                    .ok_or_else(|| format!("invalid value for {}: {}", arg, value))?;
+                if arg == "--input-scale" {
+                    options.input_scale = Some(scale);
+                } else {
+                    options.output_scale = Some(scale);
+                }
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            _ if options.path.is_none() => options.path = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    if options.tail.is_some() && options.top.is_some() {
+        return Err("--tail and --top cannot be combined".to_string());
+    }
+    if options.interactive && options.output.is_some_and(|f| f != OutputFormat::Csv) {
+        return Err("--interactive only supports --output csv".to_string());
     }
+    if options.timings && !options.stats {
+        return Err("--timings requires --stats".to_string());
+    }
+    if options.interactive && (options.checkpoint_every.is_some() || options.expect.is_some()) {
+        return Err(
+            "--interactive cannot be combined with --checkpoint-every or --expect".to_string(),
+        );
+    }
+    // these shape a report of all accounts, interactive output is per row
+    if options.interactive
+        && (options.tail.is_some()
+            || options.top.is_some()
+            || options.grand_total
+            || options.group_output_by_currency)
+    {
+        return Err(
+            "--interactive cannot be combined with --tail, --top, --grand-total or \
+             --group-output-by-currency"
+                .to_string(),
+        );
+    }
+    Ok(options)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum TransactionKind {
-    Deposit,
-    Withdrawal,
+// summary goes to stderr so stdout stays valid CSV
+fn print_stats(stats: &Stats) {
+    eprintln!("rows={}", stats.rows);
+    eprintln!("malformed={}", stats.malformed);
+    eprintln!("rejected={}", stats.rejected);
+    eprintln!("ignored={}", stats.ignored);
+    eprintln!("pending={}", stats.pending);
+    let mut types: Vec<(&String, &u64)> = stats.types.iter().collect();
+    types.sort();
+    for (ty, count) in types {
+        eprintln!("type.{}={}", ty, count);
+    }
 }
 
-#[derive(Debug, Clone)]
-struct Transaction {
-    client_id: ClientID,
-    kind: TransactionKind,
-    amount: Currency,
-    disputed: bool,
+// one line per row that referenced the transaction, in input order
+fn print_explain(tx: u64, events: &[ExplainEvent]) {
+    if events.is_empty() {
+        eprintln!("tx {}: not referenced by any row", tx);
+    }
+    for event in events {
+        let outcome = match &event.outcome {
+            ExplainOutcome::Applied => "applied".to_string(),
+            ExplainOutcome::Ignored => "ignored".to_string(),
+            ExplainOutcome::Rejected(e) => format!("rejected ({})", e),
+        };
+        eprintln!(
+            "tx {}: {} client={} {} client {} available={} held={}",
+            tx, event.ty, event.client, outcome, event.owner, event.available, event.held
+        );
+    }
 }
 
-#[derive(Default)]
-struct Engine {
-    accounts: HashMap<ClientID, Account>,
-    transactions: HashMap<TransactionID, Transaction>,
+// after the report, so writing it is included in the output time
+fn print_timings(stats: &Stats, output: Duration) {
+    eprintln!("time.parse_us={}", stats.parse_time.as_micros());
+    eprintln!("time.apply_us={}", stats.apply_time.as_micros());
+    eprintln!("time.output_us={}", output.as_micros());
 }
 
-impl Engine {
-    fn apply(&mut self, row: InputRow) {
-        let transaction_type = row.transaction_type.trim().to_ascii_lowercase();
-        match transaction_type.as_str() {
-            "deposit" => self.deposit(row.client_id, row.transaction_id, row.amount),
-            "withdrawal" => self.withdrawal(row.client_id, row.transaction_id, row.amount),
-            "dispute" => self.dispute(row.client_id, row.transaction_id),
-            "resolve" => self.resolve(row.client_id, row.transaction_id),
-            "chargeback" => self.chargeback(row.client_id, row.transaction_id),
-            _ => {}
-        }
+// a local path, - for stdin, or an http(s) url when built with the http feature
+fn open_input(path: &str) -> Result<Box<dyn io::Read>, Box<dyn std::error::Error>> {
+    if path == "-" {
+        return Ok(Box::new(io::stdin()));
     }
-
-    fn is_locked(&self, client_id: ClientID) -> bool {
-        self.accounts
-            .get(&client_id)
-            .map(|account| account.locked)
-            .unwrap_or(false)
-    }
-
-    fn get_or_create_account(&mut self, client_id: ClientID) -> &mut Account {
-        self.accounts
-            .entry(client_id)
-            .or_insert_with(Account::default)
+    if path.starts_with("http://") || path.starts_with("https://") {
+        return open_url(path);
     }
+    Ok(Box::new(File::open(path)?))
+}
 
-    fn deposit(
-        &mut self,
-        client_id: ClientID,
-        transaction_id: TransactionID,
-        amount: Option<String>,
-    ) {
-        if self.is_locked(client_id) {
-            return;
-        }
-        if self.transactions.contains_key(&transaction_id) {
-            return;
+#[cfg(feature = "http")]
+fn open_url(url: &str) -> Result<Box<dyn io::Read>, Box<dyn std::error::Error>> {
+    let response = match ureq::get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(code, _)) => {
+            return Err(format!("GET {} failed with status {}", url, code).into());
         }
+        Err(e) => return Err(format!("GET {} failed: {}", url, e).into()),
+    };
+    // other 2xx codes carry no usable body for us
+    if response.status() != 200 {
+        return Err(format!("GET {} failed with status {}", url, response.status()).into());
+    }
+    Ok(Box::new(response.into_reader()))
+}
 
-        // convert from Option<string> to Decimal or return
-        let amount = match amount {
-            Some(s) => match parse_amount(&s) {
-                Ok(v) => v,
-                Err(_) => return,
-            },
-            None => return,
-        };
+#[cfg(not(feature = "http"))]
+fn open_url(url: &str) -> Result<Box<dyn io::Read>, Box<dyn std::error::Error>> {
+    Err(format!("cannot read {}, built without the http feature", url).into())
+}
 
-        let account = self.get_or_create_account(client_id);
-        account.available += amount;
+fn print_validation(report: &ValidationReport) {
+    println!("valid={}", report.valid);
+    println!("invalid={}", report.invalid);
+    for error in &report.errors {
+        println!("error: {}", error);
+    }
+}
 
-        self.transactions.insert(
-            transaction_id,
-            Transaction {
-                client_id,
-                kind: TransactionKind::Deposit,
-                amount,
-                disputed: false,
-            },
-        );
+// line by line summary of how the report differs from the expected file
+fn print_diff(expected: &str, actual: &str) {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let differing: Vec<usize> = (0..expected.len().max(actual.len()))
+        .filter(|&i| expected.get(i) != actual.get(i))
+        .collect();
+    eprintln!(
+        "output differs from expected file in {} line(s)",
+        differing.len()
+    );
+    for &i in differing.iter().take(MAX_REPORTED_DIFFS) {
+        eprintln!("line {}:", i + 1);
+        eprintln!("  expected: {}", expected.get(i).unwrap_or(&"<missing>"));
+        eprintln!("  actual:   {}", actual.get(i).unwrap_or(&"<missing>"));
     }
+}
 
-    fn withdrawal(
-        &mut self,
-        client_id: ClientID,
-        transaction_id: TransactionID,
-        amount: Option<String>,
-    ) {
-        if self.is_locked(client_id) {
-            return;
-        }
-        if self.transactions.contains_key(&transaction_id) {
-            return;
-        }
+// stdout, or the --out file, gzip compressed for a .gz path
+enum ReportOutput {
+    Stdout(io::Stdout),
+    File(BufWriter<File>),
+    Gzip(GzEncoder<File>),
+}
 
-        // convert from Option<string> to Decimal or return
-        let amount = match amount {
-            Some(s) => match parse_amount(&s) {
-                Ok(v) => v,
-                Err(_) => return,
-            },
-            None => return,
+impl ReportOutput {
+    fn create(path: Option<&str>) -> io::Result<Self> {
+        let Some(path) = path else {
+            return Ok(ReportOutput::Stdout(io::stdout()));
         };
-
-        let account = self.get_or_create_account(client_id);
-        if account.available < amount {
-            // explicit requirement from the spec
-            return;
+        let file = File::create(path)?;
+        if path.ends_with(".gz") {
+            Ok(ReportOutput::Gzip(GzEncoder::new(
+                file,
+                Compression::default(),
+            )))
+        } else {
+            Ok(ReportOutput::File(BufWriter::new(file)))
         }
-        account.available -= amount;
-
-        self.transactions.insert(
-            transaction_id,
-            Transaction {
-                client_id,
-                kind: TransactionKind::Withdrawal,
-                amount,
-                disputed: false,
-            },
-        );
     }
 
-    fn dispute(&mut self, client_id: ClientID, transaction_id: TransactionID) {
-        if self.is_locked(client_id) {
-            return;
+    fn finish(self) -> io::Result<()> {
+        match self {
+            ReportOutput::Stdout(mut out) => out.flush(),
+            ReportOutput::File(mut out) => out.flush(),
+            // finished explicitly, dropping the encoder would hide write errors
+            ReportOutput::Gzip(out) => out.finish().map(drop),
         }
-        let amount = {
-            let t = match self.transactions.get(&transaction_id) {
-                Some(t) => t,
-                None => return,
-            };
-            // check if client mismatch, not a deposit, or already disputed
-            if t.client_id != client_id || t.kind != TransactionKind::Deposit || t.disputed {
-                return;
-            }
-            t.amount
-        };
-
-        let account = self.get_or_create_account(client_id);
-        account.available -= amount;
-        account.held += amount;
+    }
+}
 
-        if let Some(t) = self.transactions.get_mut(&transaction_id) {
-            t.disputed = true;
+impl Write for ReportOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ReportOutput::Stdout(out) => out.write(buf),
+            ReportOutput::File(out) => out.write(buf),
+            ReportOutput::Gzip(out) => out.write(buf),
         }
     }
 
-    fn resolve(&mut self, client_id: ClientID, transaction_id: TransactionID) {
-        if self.is_locked(client_id) {
-            return;
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ReportOutput::Stdout(out) => out.flush(),
+            ReportOutput::File(out) => out.flush(),
+            ReportOutput::Gzip(out) => out.flush(),
         }
+    }
+}
 
-        let amount = {
-            let t = match self.transactions.get(&transaction_id) {
-                Some(t) => t,
-                None => return,
-            };
-            // check if client mismatch, not a deposit, or transaction not disputed
-            if t.client_id != client_id || t.kind != TransactionKind::Deposit || !t.disputed {
-                return;
-            }
-            t.amount
-        };
-
-        let account = self.get_or_create_account(client_id);
-        if account.held < amount {
-            return;
-        }
-        account.held -= amount;
-        account.available += amount;
+// output settings shared by the final and the intermediate reports
+struct Report {
+    tail: Option<usize>,
+    top: Option<usize>,
+    // the dispute mode the views are computed for
+    balance_views: Option<DisputeMode>,
+    grand_total: bool,
+    group_by_currency: bool,
+    net_movement: bool,
+    scale: u32,
+    // nothing at all, not even the header, when there are no accounts
+    empty_without_clients: bool,
+    format: OutputFormat,
+}
 
-        if let Some(t) = self.transactions.get_mut(&transaction_id) {
-            t.disputed = false;
-        }
+// rows of the report in the order they are written
+fn report_rows(engine: &Engine, report: &Report) -> Vec<OutputRow> {
+    // accounts are already in client order
+    let mut clients: Vec<ClientID> = engine.accounts().keys().copied().collect();
+    if let Some(n) = report.tail {
+        // most recently active first
+        clients.sort_by_key(|c| std::cmp::Reverse(engine.accounts()[c].last_activity()));
+        clients.truncate(n);
+    }
+    if let Some(n) = report.top {
+        // largest total first, the sort is stable so ties stay in client order
+        clients.sort_by_key(|c| std::cmp::Reverse(engine.accounts()[c].total()));
+        clients.truncate(n);
     }
 
-    fn chargeback(&mut self, client_id: ClientID, transaction_id: TransactionID) {
-        if self.is_locked(client_id) {
-            return;
+    let mut rows: Vec<OutputRow> = clients
+        .into_iter()
+        .map(|client| {
+            let account = &engine.accounts()[&client];
+            let row = OutputRow::from_account(client, account, report.balance_views, report.scale);
+            if report.net_movement {
+                row.with_net_movement(account.net_movement, report.scale)
+            } else {
+                row
+            }
+        })
+        .collect();
+    if report.group_by_currency {
+        // one row per currency in code order, accounts without a currency
+        // only count towards the grand total
+        let mut by_currency: BTreeMap<&str, Vec<_>> = BTreeMap::new();
+        for account in engine.accounts().values() {
+            if let Some(currency) = account.currency() {
+                by_currency.entry(currency).or_default().push(account);
+            }
         }
-        let amount = {
-            let t = match self.transactions.get(&transaction_id) {
-                Some(t) => t,
-                None => return,
-            };
-            // check if client mismatch, not a deposit, or transaction not disputed
-            if t.client_id != client_id || t.kind != TransactionKind::Deposit || !t.disputed {
-                return;
+        for (currency, accounts) in by_currency {
+            let mut row = OutputRow::currency_total(
+                currency,
+                accounts.iter().copied(),
+                report.balance_views,
+                report.scale,
+            );
+            if report.net_movement {
+                let net: Currency = accounts.iter().map(|a| a.net_movement).sum();
+                row = row.with_net_movement(net, report.scale);
             }
-            t.amount
-        };
-
-        let account = self.get_or_create_account(client_id);
-        if account.held < amount {
-            return;
+            rows.push(row);
         }
-        account.held -= amount;
-        account.locked = true;
-
-        if let Some(t) = self.transactions.get_mut(&transaction_id) {
-            t.disputed = false;
+    }
+    if report.grand_total {
+        // summed over every account, labelled in the client column
+        let mut row = OutputRow::grand_total(
+            engine.accounts().values(),
+            report.balance_views,
+            report.scale,
+        );
+        if report.net_movement {
+            let net: Currency = engine.accounts().values().map(|a| a.net_movement).sum();
+            row = row.with_net_movement(net, report.scale);
         }
+        rows.push(row);
     }
+    rows
 }
 
-fn parse_amount(amount: &str) -> Result<Currency, String> {
-    let t = amount.trim();
-    if t.is_empty() {
-        return Err("empty amount".to_string());
+// the effective settings as `# name=value` lines, in name order
+fn write_config_header(out: &mut dyn Write, config: &Config, scale: u32) -> io::Result<()> {
+    if let serde_json::Value::Object(fields) = serde_json::to_value(config)? {
+        for (name, mut value) in fields {
+            // client id sets come out of a HashSet in no particular order
+            if let serde_json::Value::Array(items) = &mut value {
+                items.sort_by_key(|item| item.as_u64());
+            }
+            writeln!(out, "# {}={}", name, value)?;
+        }
     }
-    let mut d = Decimal::from_str(t).map_err(|_| "bad amount".to_string())?;
+    writeln!(out, "# output_scale={}", scale)
+}
 
-    // reject zero or negative amounts
-    if d <= Decimal::ZERO {
-        return Err("amount must be positive".to_string());
+fn write_report<W: io::Write>(writer: W, engine: &Engine, report: &Report) -> io::Result<()> {
+    if report.empty_without_clients && engine.accounts().is_empty() {
+        return Ok(());
     }
-
-    // Enforce max 4 decimal places.
-    // If input has more, we fail rather than silently round, to avoid spec ambiguity.
-    if d.scale() > 4 {
-        return Err("too many decimal places".to_string());
+    if report.format == OutputFormat::HeldDetail {
+        return Ok(write_held_detail(writer, engine, report.scale)?);
+    }
+    let rows = report_rows(engine, report);
+    let header = OutputRow::header(report.balance_views.is_some(), report.net_movement);
+    match report.format {
+        OutputFormat::Table => write_to_sink(&mut TableSink::new(writer, header), rows),
+        _ => write_to_sink(&mut CsvSink::new(writer, header), rows),
     }
-
-    // Normalize to exactly 4 dp for stable output.
-    d = d.round_dp(4);
-    Ok(d)
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let path = env::args().nth(1).ok_or("Please provide a CSV file path")?;
-    let file = File::open(path)?;
-
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .from_reader(file);
-
-    let mut engine = Engine::default();
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {e}");
+        std::process::exit(ERROR_EXIT_CODE);
+    }
+}
 
-    for record in csv_reader.deserialize::<InputRow>() {
-        if let Ok(row) = record {
-            engine.apply(row);
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args().skip(1).peekable();
+    let validate = args.next_if_eq("validate").is_some();
+    let options = parse_args(args)?;
+    // interactive sessions read stdin unless given a file
+    let path = match options.path {
+        Some(path) => path,
+        None if options.interactive => "-".to_string(),
+        None => return Err("Please provide a CSV file path".into()),
+    };
+    let capacity = options
+        .read_buffer_bytes
+        .unwrap_or(DEFAULT_READ_BUFFER_BYTES);
+    let input = BufReader::with_capacity(capacity, open_input(&path)?);
+
+    let config = Config {
+        allow_admin_ops: options.allow_admin_ops,
+        dedup_consecutive_rows: options.dedup_consecutive_rows,
+        strict: options.strict,
+        aliases: options.aliases,
+        max_client_id: options.max_client_id,
+        allow_dispute_when_locked: options.allow_dispute_when_locked,
+        dispute_mode: options.dispute_mode,
+        chargeback_mode: options.chargeback_mode,
+        since: options.since,
+        until: options.until,
+        exclude_untimestamped: options.exclude_untimestamped,
+        as_of_tx: options.as_of_tx,
+        pending_limit: options.pending_limit,
+        require_schema_version: options.require_schema_version,
+        input_scale: options.input_scale,
+        check_duplicate_amounts: options.check_duplicate_amounts,
+        account_filter: options.account_filter,
+        catch_panics: options.catch_panics,
+        locked_allows_deposit: options.locked_allows_deposit,
+        auto_dialect: options.auto_dialect,
+        max_field_len: options.max_field_len,
+        detect_truncation: options.detect_truncation,
+        comment: options.comment,
+        dispute_requires_funds: options.dispute_requires_funds,
+        fast_path: options.fast_path,
+        timings: options.timings,
+        require_open: options.require_open,
+        dispute_agents: options.dispute_agents,
+        overdraft_limit: options.overdraft_limit,
+        explain_tx: options.explain,
+        max_disputes: options.max_disputes,
+        precision: options.precision,
+    };
+    if validate {
+        let report = validate_reader(input, &config, MAX_REPORTED_ERRORS);
+        print_validation(&report);
+        if report.invalid > 0 {
+            std::process::exit(INVALID_INPUT_EXIT_CODE);
         }
+        return Ok(());
+    }
+    let mut engine: Engine = Engine::with_config(config);
+
+    let report = Report {
+        tail: options.tail,
+        top: options.top,
+        balance_views: options.balance_views.then_some(options.dispute_mode),
+        grand_total: options.grand_total,
+        group_by_currency: options.group_output_by_currency,
+        net_movement: options.net_movement,
+        scale: options.output_scale.unwrap_or(DEFAULT_SCALE),
+        empty_without_clients: options.emit_empty_on_no_clients,
+        // a table for a person at a terminal, CSV for files, pipes and
+        // golden file comparisons
+        format: options.output.unwrap_or_else(|| {
+            let to_terminal = options.out.is_none() && options.expect.is_none();
+            if to_terminal && io::stdout().is_terminal() {
+                OutputFormat::Table
+            } else {
+                OutputFormat::Csv
+            }
+        }),
+    };
+    let mut output = None;
+    let stats = match options.checkpoint_every {
+        _ if options.interactive => {
+            if options.emit_config_header {
+                write_config_header(&mut io::stdout(), engine.config(), report.scale)?;
+            }
+            let header = OutputRow::header(report.balance_views.is_some(), report.net_movement);
+            let mut sink = CsvSink::new(io::stdout(), header);
+            let mut written = Ok(());
+            let stats = process_reader_streaming(input, &mut engine, None, |client, account| {
+                let mut row =
+                    OutputRow::from_account(client, account, report.balance_views, report.scale);
+                if report.net_movement {
+                    row = row.with_net_movement(account.net_movement, report.scale);
+                }
+                // flushed per row so each update shows up as soon as it is applied
+                if written.is_ok() {
+                    written = sink.write_row(&row).and_then(|()| sink.finish());
+                }
+            })?;
+            written?;
+            if !(report.empty_without_clients && engine.accounts().is_empty()) {
+                sink.finish()?;
+            }
+            stats
+        }
+        Some(every) => {
+            // opened up front, the final report follows the intermediate ones
+            let out = output.insert(ReportOutput::create(options.out.as_deref())?);
+            let mut written = Ok(());
+            let stats = process_reader_checkpointed(input, &mut engine, every, |engine| {
+                // each intermediate report is followed by an empty line
+                if written.is_ok() {
+                    written = write_report(&mut *out, engine, &report).and_then(|()| writeln!(out));
+                }
+            })?;
+            written?;
+            stats
+        }
+        None => process_reader(input, &mut engine)?,
+    };
+    // --quiet wins over every diagnostic flag, errors are still reported
+    if options.stats && !options.quiet {
+        print_stats(&stats);
     }
 
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    wtr.write_record(["client", "available", "held", "total", "locked"])?;
-
-    let mut clients: Vec<ClientID> = engine.accounts.keys().copied().collect();
-    clients.sort();
-
-    for client in clients {
-        let acc = &engine.accounts[&client];
-
-        // round to max 4 dp, but avoid padding trailing zeros in output
-        let fmt = |d: Currency| d.round_dp(4).to_string();
+    let output_started = Instant::now();
+    let mut mismatch = false;
+    if let Some(path) = &options.expect {
+        // render once, print it as usual and compare the same bytes
+        let mut rendered = Vec::new();
+        if options.emit_config_header {
+            write_config_header(&mut rendered, engine.config(), report.scale)?;
+        }
+        write_report(&mut rendered, &engine, &report)?;
+        let mut out = match output {
+            Some(out) => out,
+            None => ReportOutput::create(options.out.as_deref())?,
+        };
+        out.write_all(&rendered)?;
+        out.finish()?;
+        let expected = std::fs::read_to_string(path)?;
+        let actual = String::from_utf8_lossy(&rendered);
+        mismatch = expected != actual;
+        if mismatch && !options.quiet {
+            print_diff(&expected, &actual);
+        }
+    } else if !options.interactive {
+        let mut out = match output {
+            Some(out) => out,
+            None => ReportOutput::create(options.out.as_deref())?,
+        };
+        if options.emit_config_header {
+            write_config_header(&mut out, engine.config(), report.scale)?;
+        }
+        write_report(&mut out, &engine, &report)?;
+        out.finish()?;
+    }
+    if let Some(path) = &options.dump_transactions {
+        write_transactions(File::create(path)?, &engine)?;
+    }
+    if let Some(path) = &options.dump_state {
+        engine.dump_state(File::create(path)?)?;
+    }
+    if let Some(tx) = options.explain
+        && !options.quiet
+    {
+        print_explain(tx, engine.explained());
+    }
+    if options.checksum && !options.quiet {
+        eprintln!("checksum={}", rows_checksum(&report_rows(&engine, &report)));
+    }
+    if options.timings && !options.quiet {
+        print_timings(&stats, output_started.elapsed());
+    }
 
-        wtr.write_record([
-            client.to_string(),
-            fmt(acc.available),
-            fmt(acc.held),
-            fmt(acc.total()),
-            acc.locked.to_string(),
-        ])?;
-    }
-    wtr.flush()?;
+    if mismatch {
+        std::process::exit(EXPECT_MISMATCH_EXIT_CODE);
+    }
+    if options.fail_on_locked && engine.accounts().values().any(|acc| acc.locked) {
+        std::process::exit(LOCKED_EXIT_CODE);
+    }
+    // malformed, rejected, silently ignored and never replayed rows all count
+    if options.fail_on_reject
+        && stats.malformed + stats.rejected + stats.ignored + stats.pending > 0
+    {
+        std::process::exit(REJECTED_EXIT_CODE);
+    }
 
     Ok(())
 }
-
-#[cfg(test)]
-mod tests;
@@ -0,0 +1,390 @@
+use crate::{Account, ClientID, Currency, DisputeMode, Engine, TransactionID};
+use rust_decimal::RoundingStrategy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io;
+
+/// One line of the balance report.
+///
+/// Optional columns are left out entirely when `None`, so every row of a
+/// report must use the same set of them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutputRow {
+    /// Client id, or a label such as `TOTAL` for summary rows.
+    pub client: String,
+    pub available: Currency,
+    pub held: Currency,
+    pub total: Currency,
+    /// Empty for summary rows.
+    pub locked: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pessimistic: Option<Currency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub optimistic: Option<Currency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_movement: Option<Currency>,
+}
+
+impl OutputRow {
+    /// Balances of one account, rounded to `scale` decimal places. With
+    /// `balance_views` the pessimistic and optimistic columns are added, as
+    /// seen under that dispute mode.
+    pub fn from_account(
+        client: ClientID,
+        account: &Account,
+        balance_views: Option<DisputeMode>,
+        scale: u32,
+    ) -> Self {
+        OutputRow {
+            client: client.to_string(),
+            locked: Some(account.locked),
+            ..Self::balances(account.available, account.held, balance_views, scale)
+        }
+    }
+
+    /// Summary row with balances summed over `accounts`.
+    pub fn grand_total<'a, I>(accounts: I, balance_views: Option<DisputeMode>, scale: u32) -> Self
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let (available, held) = accounts
+            .into_iter()
+            .fold((Currency::ZERO, Currency::ZERO), |(a, h), acc| {
+                (a + acc.available, h + acc.held)
+            });
+        OutputRow {
+            client: "TOTAL".to_string(),
+            locked: None,
+            ..Self::balances(available, held, balance_views, scale)
+        }
+    }
+
+    /// Summary row for the accounts in `currency`, labelled `TOTAL <currency>`.
+    pub fn currency_total<'a, I>(
+        currency: &str,
+        accounts: I,
+        balance_views: Option<DisputeMode>,
+        scale: u32,
+    ) -> Self
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        OutputRow {
+            client: format!("TOTAL {}", currency),
+            ..Self::grand_total(accounts, balance_views, scale)
+        }
+    }
+
+    // total and views are derived from the rounded available and held, so
+    // the printed columns add up
+    fn balances(
+        available: Currency,
+        held: Currency,
+        balance_views: Option<DisputeMode>,
+        scale: u32,
+    ) -> Self {
+        let rounded = Account {
+            available: format_amount(available, scale),
+            held: format_amount(held, scale),
+            ..Account::default()
+        };
+        OutputRow {
+            client: String::new(),
+            available: rounded.available,
+            held: rounded.held,
+            total: format_amount(rounded.total(), scale),
+            locked: None,
+            pessimistic: balance_views
+                .map(|mode| format_amount(rounded.pessimistic_balance(mode), scale)),
+            optimistic: balance_views
+                .map(|mode| format_amount(rounded.optimistic_balance(mode), scale)),
+            net_movement: None,
+        }
+    }
+
+    /// Adds the `net_movement` column, see [`Account::net_movement`].
+    pub fn with_net_movement(self, net_movement: Currency, scale: u32) -> Self {
+        OutputRow {
+            net_movement: Some(format_amount(net_movement, scale)),
+            ..self
+        }
+    }
+
+    /// Column names matching the serialized rows.
+    pub fn header(balance_views: bool, net_movement: bool) -> Vec<&'static str> {
+        let mut header = vec!["client", "available", "held", "total", "locked"];
+        if balance_views {
+            header.extend(["pessimistic", "optimistic"]);
+        }
+        if net_movement {
+            header.push("net_movement");
+        }
+        header
+    }
+
+    /// Values in [`OutputRow::header`] order, as printed in the CSV report.
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields = vec![
+            self.client.clone(),
+            self.available.to_string(),
+            self.held.to_string(),
+            self.total.to_string(),
+            self.locked
+                .map(|locked| locked.to_string())
+                .unwrap_or_default(),
+        ];
+        fields.extend(self.pessimistic.map(|v| v.to_string()));
+        fields.extend(self.optimistic.map(|v| v.to_string()));
+        fields.extend(self.net_movement.map(|v| v.to_string()));
+        fields
+    }
+}
+
+/// Hex SHA-256 over `rows`, for checking that two runs produced the same
+/// report. Each row is hashed as its CSV line; the lines are sorted first so
+/// the result does not depend on the order the rows were written in.
+pub fn rows_checksum<'a, I>(rows: I) -> String
+where
+    I: IntoIterator<Item = &'a OutputRow>,
+{
+    let mut lines: Vec<String> = rows.into_iter().map(|row| row.fields().join(",")).collect();
+    lines.sort_unstable();
+    let mut hasher = Sha256::new();
+    for line in &lines {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// round to max `scale` dp, half away from zero like PrecisionPolicy::Round,
+// but avoid padding trailing zeros in output
+fn format_amount(amount: Currency, scale: u32) -> Currency {
+    let mut rounded = amount.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero);
+    // decimal zero keeps its sign bit, a negative zero would print as -0
+    if rounded.is_zero() {
+        rounded.set_sign_positive(true);
+    }
+    rounded
+}
+
+/// Writes `header` followed by `rows` as CSV.
+///
+/// The header is written even when there are no rows. Rows go through serde,
+/// so string fields containing delimiters or quotes are escaped properly.
+pub fn write_rows<W, T, I>(writer: W, header: &[&str], rows: I) -> Result<(), csv::Error>
+where
+    W: io::Write,
+    T: Serialize,
+    I: IntoIterator<Item = T>,
+{
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    wtr.write_record(header)?;
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Destination for the rows of a balance report.
+pub trait OutputSink {
+    fn write_row(&mut self, row: &OutputRow) -> io::Result<()>;
+    /// Called after the last row, e.g. to flush buffered output. May be
+    /// called again after further rows.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Writes the report as CSV, header first.
+pub struct CsvSink<W: io::Write> {
+    writer: csv::Writer<W>,
+    header: Vec<&'static str>,
+    header_written: bool,
+}
+
+impl<W: io::Write> CsvSink<W> {
+    pub fn new(writer: W, header: Vec<&'static str>) -> Self {
+        CsvSink {
+            writer: csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(writer),
+            header,
+            header_written: false,
+        }
+    }
+
+    // the header is written even when there are no rows
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.writer.write_record(&self.header)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: io::Write> OutputSink for CsvSink<W> {
+    fn write_row(&mut self, row: &OutputRow) -> io::Result<()> {
+        self.write_header()?;
+        self.writer.serialize(row)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+/// Writes the report as a bordered table for reading in a terminal, with
+/// numbers right-aligned. Column widths depend on every row, so nothing is
+/// written before [`OutputSink::finish`].
+pub struct TableSink<W: io::Write> {
+    writer: W,
+    header: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl<W: io::Write> TableSink<W> {
+    pub fn new(writer: W, header: Vec<&'static str>) -> Self {
+        TableSink {
+            writer,
+            header,
+            rows: Vec::new(),
+        }
+    }
+
+    fn write_border(&mut self, widths: &[usize]) -> io::Result<()> {
+        let dashes: Vec<String> = widths.iter().map(|&width| "-".repeat(width + 2)).collect();
+        writeln!(self.writer, "+{}+", dashes.join("+"))
+    }
+
+    fn write_line<S: AsRef<str>>(&mut self, cells: &[S], widths: &[usize]) -> io::Result<()> {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&self.header)
+            .zip(widths)
+            .map(|((cell, &name), &width)| {
+                // every column but locked holds a number or the TOTAL label
+                if name == "locked" {
+                    format!(" {:<width$} ", cell.as_ref())
+                } else {
+                    format!(" {:>width$} ", cell.as_ref())
+                }
+            })
+            .collect();
+        writeln!(self.writer, "|{}|", cells.join("|"))
+    }
+}
+
+impl<W: io::Write> OutputSink for TableSink<W> {
+    fn write_row(&mut self, row: &OutputRow) -> io::Result<()> {
+        self.rows.push(row.fields());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut widths: Vec<usize> = self.header.iter().map(|h| h.len()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let header = self.header.clone();
+        self.write_border(&widths)?;
+        self.write_line(&header, &widths)?;
+        self.write_border(&widths)?;
+        for row in std::mem::take(&mut self.rows) {
+            self.write_line(&row, &widths)?;
+        }
+        self.write_border(&widths)?;
+        self.writer.flush()
+    }
+}
+
+/// Collects rows in memory, mostly useful in tests.
+#[derive(Debug, Default)]
+pub struct VecSink {
+    pub rows: Vec<OutputRow>,
+}
+
+impl OutputSink for VecSink {
+    fn write_row(&mut self, row: &OutputRow) -> io::Result<()> {
+        self.rows.push(row.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends every row to `sink`, then finishes it.
+pub fn write_to_sink<S, I>(sink: &mut S, rows: I) -> io::Result<()>
+where
+    S: OutputSink + ?Sized,
+    I: IntoIterator<Item = OutputRow>,
+{
+    for row in rows {
+        sink.write_row(&row)?;
+    }
+    sink.finish()
+}
+
+#[derive(Serialize)]
+struct TransactionRow {
+    tx: TransactionID,
+    client: ClientID,
+    kind: &'static str,
+    amount: Currency,
+    disputed: bool,
+}
+
+/// Writes the engine's recorded transactions as CSV, ordered by id.
+pub fn write_transactions<W: io::Write>(writer: W, engine: &Engine) -> Result<(), csv::Error> {
+    let rows = engine
+        .transactions()
+        .into_iter()
+        .map(|(tx, t)| TransactionRow {
+            tx,
+            client: t.client_id(),
+            kind: t.kind().as_str(),
+            amount: t.amount(),
+            disputed: t.is_disputed(),
+        });
+    write_rows(
+        writer,
+        &["tx", "client", "kind", "amount", "disputed"],
+        rows,
+    )
+}
+
+#[derive(Serialize)]
+struct HeldRow {
+    client: ClientID,
+    tx: TransactionID,
+    held: Currency,
+}
+
+/// Writes one row per transaction with an open dispute, ordered by client
+/// then transaction id. The amounts of a client add up to its `held`.
+pub fn write_held_detail<W: io::Write>(
+    writer: W,
+    engine: &Engine,
+    scale: u32,
+) -> Result<(), csv::Error> {
+    let mut disputed = engine.disputed_transactions();
+    disputed.sort_by_key(|&(tx, client, _)| (client, tx));
+    let rows = disputed.into_iter().map(|(tx, client, amount)| HeldRow {
+        client,
+        tx,
+        held: format_amount(amount, scale),
+    });
+    write_rows(writer, &["client", "tx", "held"], rows)
+}
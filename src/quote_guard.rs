@@ -0,0 +1,173 @@
+use std::io::{self, BufRead, Read};
+
+/// Longest line, in fields of the maximum length, the guard buffers.
+pub(crate) const MAX_LINE_FIELDS: usize = 32;
+
+/// Keeps an unbalanced quote from swallowing the rest of the input.
+///
+/// A quoted field may span lines in CSV, so a stray `"` makes the reader
+/// treat everything up to the next quote, often the end of the file, as one
+/// field. No column of the input format can hold a line break, so a line
+/// with an odd number of quotes is passed on as a single quoted field
+/// instead: the record is counted as malformed and the next line is read
+/// as usual. Comment lines are left alone.
+///
+/// It also bounds what is buffered: a field growing past `max_field_len`
+/// bytes is cut one byte after the limit and the rest of its line skipped,
+/// so the record is rejected as too long without being read in full.
+/// Delimiters inside quotes do not end a field. A line longer than
+/// [`MAX_LINE_FIELDS`] such fields is cut as well and passed on as a single
+/// quoted field, which is then rejected as too long.
+pub(crate) struct QuoteGuard<R> {
+    inner: R,
+    comment: Option<u8>,
+    delimiter: u8,
+    max_field_len: usize,
+    line: Vec<u8>,
+    // whether the last line read ended with a line break
+    terminated: bool,
+    pos: usize,
+    line_number: u64,
+}
+
+impl<R: BufRead> QuoteGuard<R> {
+    pub(crate) fn new(inner: R, comment: Option<u8>, delimiter: u8, max_field_len: usize) -> Self {
+        QuoteGuard {
+            inner,
+            comment,
+            delimiter,
+            max_field_len,
+            line: Vec::new(),
+            terminated: true,
+            pos: 0,
+            line_number: 0,
+        }
+    }
+
+    /// False when the last line read had no line break, i.e. the input
+    /// ended in the middle of it.
+    pub(crate) fn ends_with_newline(&self) -> bool {
+        self.terminated
+    }
+
+    fn next_line(&mut self) -> io::Result<()> {
+        self.line.clear();
+        self.pos = 0;
+        let Some(overlong) = self.read_line()? else {
+            return Ok(());
+        };
+        self.line_number += 1;
+        let quotes = self.line.iter().filter(|&&b| b == b'"').count();
+        if (quotes % 2 == 0 && !overlong) || self.line.first() == self.comment.as_ref() {
+            return Ok(());
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            line = self.line_number,
+            "unbalanced quote or long line, row skipped"
+        );
+        let ending = if self.line.ends_with(b"\r\n") {
+            2
+        } else {
+            usize::from(self.line.ends_with(b"\n"))
+        };
+        let (content, ending) = self.line.split_at(self.line.len() - ending);
+        let mut quoted = Vec::with_capacity(self.line.len() + quotes + 2);
+        quoted.push(b'"');
+        for &b in content {
+            if b == b'"' {
+                quoted.push(b'"');
+            }
+            quoted.push(b);
+        }
+        quoted.push(b'"');
+        quoted.extend_from_slice(ending);
+        self.line = quoted;
+        Ok(())
+    }
+
+    // reads one line into `line` in chunks, cutting it at the first field
+    // over the limit or once the line itself is too long; None at the end
+    // of the input, else whether the line was too long
+    fn read_line(&mut self) -> io::Result<Option<bool>> {
+        let max_line_len = self.max_field_len.saturating_mul(MAX_LINE_FIELDS);
+        let mut read = false;
+        let mut terminated = false;
+        let mut field = 0;
+        let mut quoted = false;
+        let mut cut = false;
+        let mut overlong = false;
+        loop {
+            let chunk = self.inner.fill_buf()?;
+            if chunk.is_empty() {
+                break;
+            }
+            read = true;
+            let end = chunk.iter().position(|&b| b == b'\n');
+            let take = end.map_or(chunk.len(), |i| i + 1);
+            if !cut {
+                let mut keep = take;
+                for (i, &b) in chunk[..take].iter().enumerate() {
+                    // an escaped "" flips twice and stays inside the quotes
+                    if b == b'"' {
+                        quoted = !quoted;
+                    } else if b == self.delimiter && !quoted {
+                        field = 0;
+                    } else if b != b'\r' && b != b'\n' {
+                        field += 1;
+                    }
+                    overlong = self.line.len() + i >= max_line_len;
+                    if field > self.max_field_len || overlong {
+                        keep = i + 1;
+                        cut = true;
+                        break;
+                    }
+                }
+                self.line.extend_from_slice(&chunk[..keep]);
+            }
+            self.inner.consume(take);
+            if end.is_some() {
+                terminated = true;
+                if cut {
+                    self.line.push(b'\n');
+                }
+                break;
+            }
+        }
+        #[cfg(feature = "tracing")]
+        if cut {
+            tracing::debug!(
+                line = self.line_number + 1,
+                "field or line too long, line cut"
+            );
+        }
+        if !read {
+            return Ok(None);
+        }
+        self.terminated = terminated;
+        Ok(Some(overlong))
+    }
+}
+
+impl<R: BufRead> Read for QuoteGuard<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for QuoteGuard<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.line.len() {
+            self.next_line()?;
+        }
+        Ok(&self.line[self.pos..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos += amount;
+    }
+}
@@ -0,0 +1,36 @@
+use crate::ClientID;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Per-client throttle for streaming callbacks.
+///
+/// Each client gets at most `per_second` callbacks per second, spaced evenly.
+/// Clients are throttled independently of each other.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    interval: Duration,
+    next_allowed: HashMap<ClientID, Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs(1) / per_second.max(1),
+            next_allowed: HashMap::new(),
+        }
+    }
+
+    /// Blocks until `client` may emit again and reserves the next slot.
+    pub fn wait(&mut self, client: ClientID) {
+        let now = Instant::now();
+        let slot = match self.next_allowed.get(&client) {
+            Some(&next) if next > now => {
+                thread::sleep(next - now);
+                next
+            }
+            _ => now,
+        };
+        self.next_allowed.insert(client, slot + self.interval);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{Account, Amount, ClientID, Config, Engine, Transaction, TransactionID};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+
+// bump when the layout below changes
+const SNAPSHOT_VERSION: u32 = 4;
+
+#[derive(Serialize)]
+struct SnapshotRef<'a, A> {
+    version: u32,
+    accounts: &'a BTreeMap<ClientID, Account<A>>,
+    transactions: &'a BTreeMap<TransactionID, Transaction<A>>,
+    activity: u64,
+    applied_batches: &'a BTreeSet<String>,
+    pending: &'a BTreeMap<TransactionID, Vec<(ClientID, String)>>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot<A> {
+    version: u32,
+    accounts: BTreeMap<ClientID, Account<A>>,
+    transactions: BTreeMap<TransactionID, Transaction<A>>,
+    activity: u64,
+    applied_batches: BTreeSet<String>,
+    pending: BTreeMap<TransactionID, Vec<(ClientID, String)>>,
+}
+
+// the fields after `version`; binary snapshots are not self-describing, so
+// the version is read on its own first and an older layout is never decoded
+#[cfg(feature = "bincode")]
+#[derive(Deserialize)]
+struct SnapshotBody<A> {
+    accounts: BTreeMap<ClientID, Account<A>>,
+    transactions: BTreeMap<TransactionID, Transaction<A>>,
+    activity: u64,
+    applied_batches: BTreeSet<String>,
+    pending: BTreeMap<TransactionID, Vec<(ClientID, String)>>,
+}
+
+// bump when the layout of StateDump changes
+const STATE_DUMP_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct StateDumpRef<'a, A> {
+    version: u32,
+    config: &'a Config,
+    accounts: &'a BTreeMap<ClientID, Account<A>>,
+    transactions: &'a BTreeMap<TransactionID, Transaction<A>>,
+}
+
+/// Document written by [`Engine::dump_state`], for reading back in tools.
+#[derive(Debug, Deserialize)]
+pub struct StateDump<A = crate::Currency> {
+    pub version: u32,
+    pub config: Config,
+    pub accounts: BTreeMap<ClientID, Account<A>>,
+    pub transactions: BTreeMap<TransactionID, Transaction<A>>,
+}
+
+/// Reason a snapshot could not be written or restored.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Format(String),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot i/o error: {}", e),
+            SnapshotError::Format(e) => write!(f, "invalid snapshot: {}", e),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(e: serde_json::Error) -> Self {
+        SnapshotError::Format(e.to_string())
+    }
+}
+
+impl<A: Amount + Serialize + DeserializeOwned> Engine<A> {
+    fn snapshot_ref(&self) -> SnapshotRef<'_, A> {
+        SnapshotRef {
+            version: SNAPSHOT_VERSION,
+            accounts: &self.accounts,
+            transactions: &self.transactions,
+            activity: self.activity,
+            applied_batches: &self.applied_batches,
+            pending: &self.pending,
+        }
+    }
+
+    // config is not part of the snapshot, the caller provides it on restore
+    fn from_snapshot(snapshot: Snapshot<A>, config: Config) -> Result<Self, SnapshotError> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+        }
+        Ok(Engine {
+            accounts: snapshot.accounts,
+            transactions: snapshot.transactions,
+            activity: snapshot.activity,
+            applied_batches: snapshot.applied_batches,
+            parked: snapshot.pending.values().map(Vec::len).sum(),
+            pending: snapshot.pending,
+            ..Engine::with_config(config)
+        })
+    }
+
+    /// Writes accounts and recorded transactions as JSON.
+    pub fn save_snapshot<W: io::Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        serde_json::to_writer(writer, &self.snapshot_ref())?;
+        Ok(())
+    }
+
+    /// Restores an engine written by [`Engine::save_snapshot`].
+    pub fn load_snapshot<R: io::Read>(reader: R, config: Config) -> Result<Self, SnapshotError> {
+        // the version is checked before the rest of an older layout is decoded
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let version = value.get("version").and_then(serde_json::Value::as_u64);
+        if let Some(version) = version.filter(|&v| v != u64::from(SNAPSHOT_VERSION)) {
+            return Err(SnapshotError::UnsupportedVersion(
+                u32::try_from(version).unwrap_or(u32::MAX),
+            ));
+        }
+        Self::from_snapshot(serde_json::from_value(value)?, config)
+    }
+
+    /// Like [`Engine::save_snapshot`], in a compact binary encoding.
+    #[cfg(feature = "bincode")]
+    pub fn save_snapshot_bin<W: io::Write>(&self, mut writer: W) -> Result<(), SnapshotError> {
+        bincode::serde::encode_into_std_write(
+            self.snapshot_ref(),
+            &mut writer,
+            bincode::config::standard(),
+        )
+        .map_err(|e| SnapshotError::Format(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Restores an engine written by [`Engine::save_snapshot_bin`].
+    #[cfg(feature = "bincode")]
+    pub fn load_snapshot_bin<R: io::Read>(
+        mut reader: R,
+        config: Config,
+    ) -> Result<Self, SnapshotError> {
+        let format = |e: bincode::error::DecodeError| SnapshotError::Format(e.to_string());
+        let version: u32 =
+            bincode::serde::decode_from_std_read(&mut reader, bincode::config::standard())
+                .map_err(format)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let body: SnapshotBody<A> =
+            bincode::serde::decode_from_std_read(&mut reader, bincode::config::standard())
+                .map_err(format)?;
+        let snapshot = Snapshot {
+            version,
+            accounts: body.accounts,
+            transactions: body.transactions,
+            activity: body.activity,
+            applied_batches: body.applied_batches,
+            pending: body.pending,
+        };
+        Self::from_snapshot(snapshot, config)
+    }
+}
+
+impl<A: Amount + Serialize> Engine<A> {
+    /// Writes config, accounts and transactions as one pretty-printed,
+    /// versioned JSON document for debugging. Unlike a snapshot it is not
+    /// meant to be restored from.
+    pub fn dump_state<W: io::Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        let dump = StateDumpRef {
+            version: STATE_DUMP_VERSION,
+            config: &self.config,
+            accounts: &self.accounts,
+            transactions: &self.transactions,
+        };
+        serde_json::to_writer_pretty(writer, &dump)?;
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 use super::*;
 use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
 use rust_decimal_macros::dec;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
 
 fn mk_row(typ: &str, client: ClientID, tx: TransactionID, amount: Option<&str>) -> InputRow {
     InputRow {
@@ -8,12 +10,13 @@ fn mk_row(typ: &str, client: ClientID, tx: TransactionID, amount: Option<&str>)
         client_id: client,
         transaction_id: tx,
         amount: amount.map(|s| s.to_string()),
+        ..InputRow::default()
     }
 }
 
 #[test]
 fn deposit_then_withdraw_updates_balances() {
-    let mut engine = Engine::default();
+    let mut engine: Engine = Engine::default();
     engine.apply(mk_row("deposit", 1, 1, Some("10")));
     engine.apply(mk_row("withdrawal", 1, 2, Some("4")));
 
@@ -25,7 +28,7 @@ fn deposit_then_withdraw_updates_balances() {
 
 #[test]
 fn negative_amount_is_rejected() {
-    let mut engine = Engine::default();
+    let mut engine: Engine = Engine::default();
     engine.apply(mk_row("deposit", 1, 1, Some("-1")));
     assert!(!engine.accounts.contains_key(&1));
     assert!(!engine.transactions.contains_key(&1));
@@ -36,7 +39,7 @@ fn negative_amount_is_rejected() {
 
 #[test]
 fn disputes_apply_only_to_deposits() {
-    let mut engine = Engine::default();
+    let mut engine: Engine = Engine::default();
     engine.apply(mk_row("deposit", 1, 1, Some("5")));
     engine.apply(mk_row("withdrawal", 1, 2, Some("2")));
 
@@ -55,7 +58,7 @@ fn disputes_apply_only_to_deposits() {
 
 #[test]
 fn withdrawal_more_than_available_is_ignored() {
-    let mut engine = Engine::default();
+    let mut engine: Engine = Engine::default();
     engine.apply(mk_row("deposit", 1, 1, Some("5")));
     engine.apply(mk_row("withdrawal", 1, 2, Some("10")));
 
@@ -65,6 +68,20 @@ fn withdrawal_more_than_available_is_ignored() {
     assert!(!engine.transactions.contains_key(&2));
 }
 
+#[test]
+fn withdrawal_of_exactly_available_empties_the_account() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("5")));
+    engine.apply(mk_row("withdrawal", 1, 2, Some("5")));
+
+    // boundary: only a larger amount is refused
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.total(), dec!(0));
+    assert!(engine.transactions.contains_key(&2));
+    assert_eq!(engine.ignored_count(), 0);
+}
+
 #[test]
 fn parse_amount_rejects_zero_and_too_many_decimals() {
     assert!(parse_amount("0").is_err());
@@ -73,9 +90,50 @@ fn parse_amount_rejects_zero_and_too_many_decimals() {
     assert_eq!(parse_amount("1.2345").unwrap(), dec!(1.2345));
 }
 
+#[test]
+fn precision_policies_bring_amounts_to_the_scale() {
+    let parse = |amount, policy| parse_amount_with_policy(amount, 4, policy);
+    assert_eq!(parse("1.23456", PrecisionPolicy::Round), Ok(dec!(1.2346)));
+    assert_eq!(
+        parse("1.23456", PrecisionPolicy::Truncate),
+        Ok(dec!(1.2345))
+    );
+    assert_eq!(parse("0.00005", PrecisionPolicy::Round), Ok(dec!(0.0001)));
+    // nothing left at the scale, under every policy
+    for policy in [
+        PrecisionPolicy::Reject,
+        PrecisionPolicy::Round,
+        PrecisionPolicy::Truncate,
+    ] {
+        assert!(parse("0.00004", policy).is_err());
+    }
+    assert_eq!(
+        parse("0.00004", PrecisionPolicy::Round),
+        Err("amount must be positive".to_string())
+    );
+    assert!(parse("0.00005", PrecisionPolicy::Truncate).is_err());
+}
+
+#[test]
+fn deposit_rounding_to_zero_is_never_recorded() {
+    for precision in [PrecisionPolicy::Round, PrecisionPolicy::Truncate] {
+        let config = Config {
+            precision,
+            ..Config::default()
+        };
+        let mut engine: Engine = Engine::with_config(config);
+        engine.apply(mk_row("deposit", 1, 1, Some("0.00004")));
+        engine.apply(mk_row("deposit", 1, 2, Some("1.00004")));
+
+        assert!(!engine.transactions.contains_key(&1));
+        assert_eq!(engine.accounts[&1].available, dec!(1));
+        assert_eq!(engine.ignored_count(), 1);
+    }
+}
+
 #[test]
 fn dispute_can_make_available_negative_per_spec() {
-    let mut engine = Engine::default();
+    let mut engine: Engine = Engine::default();
     engine.apply(mk_row("deposit", 1, 1, Some("100")));
     engine.apply(mk_row("withdrawal", 1, 2, Some("100")));
 
@@ -97,3 +155,2215 @@ fn decimal_output_keeps_four_places() {
     let d = Decimal::new(2, 0); // 2
     assert_eq!(d.round_dp(4).to_string(), "2");
 }
+
+#[test]
+fn reset_requires_admin_ops_and_records_audit_transaction() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("reset", 1, 2, None));
+    assert_eq!(engine.accounts[&1].available, dec!(10));
+    assert!(!engine.transactions.contains_key(&2));
+
+    let mut engine: Engine = Engine::with_config(Config {
+        allow_admin_ops: true,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("reset", 1, 2, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(0));
+    assert!(!acc.locked);
+
+    let t = &engine.transactions[&2];
+    assert_eq!(t.kind, TransactionKind::Reset);
+    assert_eq!(t.amount, dec!(10));
+}
+
+#[test]
+fn reset_closes_open_disputes_but_keeps_the_dispute_count() {
+    let mut engine: Engine = Engine::with_config(Config {
+        allow_admin_ops: true,
+        max_disputes: Some(1),
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("reset", 1, 3, None));
+
+    assert!(engine.disputed_transactions().is_empty());
+    assert!(!engine.transactions[&1].disputed);
+    // nothing for a repair to bring back
+    assert!(engine.recompute_held().is_empty());
+    assert_eq!(engine.accounts[&1].held, dec!(0));
+
+    assert_eq!(engine.accounts[&1].disputes, 1);
+    let result = engine.try_apply(mk_row("dispute", 1, 2, None));
+    assert!(matches!(
+        result,
+        Err(ApplyError::DisputeLimitExceeded { client: 1 })
+    ));
+}
+
+/// Integer minor units (1/100), used to exercise the engine with a non-decimal amount type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Cents(i64);
+
+impl Add for Cents {
+    type Output = Cents;
+    fn add(self, rhs: Cents) -> Cents {
+        Cents(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Cents {
+    type Output = Cents;
+    fn sub(self, rhs: Cents) -> Cents {
+        Cents(self.0 - rhs.0)
+    }
+}
+
+impl AddAssign for Cents {
+    fn add_assign(&mut self, rhs: Cents) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for Cents {
+    fn sub_assign(&mut self, rhs: Cents) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Amount for Cents {
+    fn parse(amount: &str) -> Result<Self, String> {
+        amount
+            .trim()
+            .parse::<i64>()
+            .map(Cents)
+            .map_err(|_| "bad amount".to_string())
+    }
+
+    fn from_currency(value: Currency) -> Self {
+        Cents((value * dec!(100)).trunc().to_i64().unwrap_or(i64::MAX))
+    }
+}
+
+fn run_dispute_scenario<A: Amount>() -> Engine<A> {
+    let mut engine = Engine::<A>::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("100")));
+    engine.apply(mk_row("withdrawal", 1, 2, Some("30")));
+    engine.apply(mk_row("deposit", 2, 3, Some("50")));
+    engine.apply(mk_row("dispute", 2, 3, None));
+    engine.apply(mk_row("withdrawal", 1, 4, Some("-5")));
+    engine
+}
+
+#[test]
+fn engine_works_with_decimal_amounts() {
+    let engine = run_dispute_scenario::<Decimal>();
+    assert_eq!(engine.accounts[&1].available, dec!(70));
+    assert_eq!(engine.accounts[&2].available, dec!(0));
+    assert_eq!(engine.accounts[&2].held, dec!(50));
+    assert_eq!(engine.accounts[&2].total(), dec!(50));
+}
+
+#[test]
+fn engine_works_with_integer_cents_amounts() {
+    let engine = run_dispute_scenario::<Cents>();
+    assert_eq!(engine.accounts[&1].available, Cents(70));
+    assert_eq!(engine.accounts[&2].available, Cents(0));
+    assert_eq!(engine.accounts[&2].held, Cents(50));
+    assert_eq!(engine.accounts[&2].total(), Cents(50));
+    assert!(!engine.transactions.contains_key(&4));
+}
+
+#[test]
+fn amount_type_without_rounding_refuses_other_precision_policies() {
+    let mut engine = Engine::<Cents>::with_config(Config {
+        precision: PrecisionPolicy::Round,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("100")));
+    assert!(engine.accounts.is_empty());
+    assert!(engine.transactions.is_empty());
+}
+
+#[test]
+fn consecutive_duplicate_dispute_rows_are_dropped_when_enabled() {
+    let mut engine: Engine = Engine::with_config(Config {
+        dedup_consecutive_rows: true,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("resolve", 1, 1, None));
+    engine.apply(mk_row("resolve", 1, 1, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(10));
+    assert_eq!(acc.held, dec!(0));
+
+    // a doubled dispute is dropped, so the following resolve releases the funds
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("resolve", 1, 1, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(10));
+    assert_eq!(acc.held, dec!(0));
+    assert!(!engine.transactions[&1].disputed);
+}
+
+#[test]
+fn non_consecutive_repeats_are_not_deduplicated() {
+    let mut engine: Engine = Engine::with_config(Config {
+        dedup_consecutive_rows: true,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("resolve", 1, 1, None));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(10));
+}
+
+#[test]
+fn unknown_transaction_type_is_reported_by_try_apply() {
+    let mut engine: Engine = Engine::default();
+    let err = engine
+        .try_apply(mk_row("withdraw", 1, 1, Some("5")))
+        .unwrap_err();
+    assert_eq!(
+        err,
+        ApplyError::UnknownTransaction {
+            ty: "withdraw".to_string()
+        }
+    );
+    assert!(engine.accounts.is_empty());
+}
+
+#[test]
+fn strict_mode_stops_on_unknown_transaction_type() {
+    let csv = "type,client,tx,amount\ndeposit,1,1,10\nwithdraw,1,2,5\ndeposit,1,3,1\n";
+
+    let mut lenient: Engine = Engine::default();
+    assert!(process_reader(csv.as_bytes(), &mut lenient).is_ok());
+    assert_eq!(lenient.accounts[&1].available, dec!(11));
+
+    let mut strict: Engine = Engine::with_config(Config {
+        strict: true,
+        ..Config::default()
+    });
+    let err = process_reader(csv.as_bytes(), &mut strict).unwrap_err();
+    assert_eq!(
+        err,
+        ApplyError::UnknownTransaction {
+            ty: "withdraw".to_string()
+        }
+    );
+    assert_eq!(strict.accounts[&1].available, dec!(10));
+}
+
+#[test]
+fn transaction_type_ignores_case_and_internal_whitespace() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row(" De posit\t", 1, 1, Some("10")));
+    engine.apply(mk_row("WITH\tDRAWAL", 1, 2, Some("4")));
+    assert_eq!(engine.accounts[&1].available, dec!(6));
+}
+
+#[test]
+fn configured_aliases_map_to_transaction_types() {
+    let aliases = HashMap::from([
+        ("dep".to_string(), "deposit".to_string()),
+        ("wd".to_string(), "withdrawal".to_string()),
+    ]);
+    let mut engine: Engine = Engine::with_config(Config {
+        aliases,
+        ..Config::default()
+    });
+    engine.apply(mk_row("DEP", 1, 1, Some("10")));
+    engine.apply(mk_row("wd", 1, 2, Some("4")));
+    assert_eq!(engine.accounts[&1].available, dec!(6));
+
+    // without the alias table the short names are unknown
+    let mut engine: Engine = Engine::default();
+    assert!(engine.try_apply(mk_row("dep", 1, 1, Some("10"))).is_err());
+}
+
+#[test]
+fn merge_combines_disjoint_engines() {
+    let mut left: Engine = Engine::default();
+    left.apply(mk_row("deposit", 1, 1, Some("10")));
+    left.apply(mk_row("dispute", 1, 1, None));
+
+    let mut right: Engine = Engine::default();
+    right.apply(mk_row("deposit", 2, 2, Some("5")));
+    right.apply(mk_row("withdrawal", 2, 3, Some("1")));
+
+    left.merge(right).unwrap();
+    assert_eq!(left.accounts.len(), 2);
+    assert_eq!(left.accounts[&1].held, dec!(10));
+    assert_eq!(left.accounts[&2].available, dec!(4));
+    assert_eq!(left.transactions.len(), 3);
+
+    // merged transactions are live, so the dispute can still be resolved
+    left.apply(mk_row("resolve", 1, 1, None));
+    assert_eq!(left.accounts[&1].available, dec!(10));
+}
+
+#[test]
+fn merge_rejects_overlapping_ids_and_leaves_engine_unchanged() {
+    let mut left: Engine = Engine::default();
+    left.apply(mk_row("deposit", 1, 1, Some("10")));
+
+    let mut right: Engine = Engine::default();
+    right.apply(mk_row("deposit", 2, 1, Some("5")));
+    assert_eq!(
+        left.merge(right),
+        Err(MergeError::OverlappingTransaction(1))
+    );
+    assert_eq!(left.accounts.len(), 1);
+
+    let mut right: Engine = Engine::default();
+    right.apply(mk_row("deposit", 1, 2, Some("5")));
+    assert_eq!(left.merge(right), Err(MergeError::OverlappingClient(1)));
+    assert_eq!(left.accounts[&1].available, dec!(10));
+    assert!(!left.transactions.contains_key(&2));
+}
+
+#[test]
+fn last_activity_follows_most_recent_row() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 2, 2, Some("10")));
+    assert!(engine.accounts[&2].last_activity() > engine.accounts[&1].last_activity());
+
+    engine.apply(mk_row("dispute", 1, 1, None));
+    assert!(engine.accounts[&1].last_activity() > engine.accounts[&2].last_activity());
+}
+
+#[test]
+fn client_id_above_configured_max_is_rejected() {
+    let mut engine: Engine = Engine::with_config(Config {
+        max_client_id: Some(100),
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 100, 1, Some("10")));
+    assert_eq!(
+        engine.try_apply(mk_row("deposit", 101, 2, Some("10"))),
+        Err(ApplyError::ClientIdOutOfRange { client: 101 })
+    );
+    assert!(engine.accounts.contains_key(&100));
+    assert!(!engine.accounts.contains_key(&101));
+}
+
+#[test]
+fn strict_mode_reports_out_of_range_client_ids() {
+    let csv = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,70000,2,5\n";
+
+    let mut lenient: Engine = Engine::default();
+    assert!(process_reader(csv.as_bytes(), &mut lenient).is_ok());
+    assert_eq!(lenient.accounts.len(), 1);
+
+    let mut strict: Engine = Engine::with_config(Config {
+        strict: true,
+        ..Config::default()
+    });
+    assert_eq!(
+        process_reader(csv.as_bytes(), &mut strict),
+        Err(ApplyError::ClientIdOutOfRange { client: 70000 })
+    );
+
+    let csv = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,500,2,5\n";
+    let mut strict: Engine = Engine::with_config(Config {
+        strict: true,
+        max_client_id: Some(100),
+        ..Config::default()
+    });
+    assert_eq!(
+        process_reader(csv.as_bytes(), &mut strict),
+        Err(ApplyError::ClientIdOutOfRange { client: 500 })
+    );
+}
+
+#[test]
+fn balance_views_bracket_open_disputes() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("dispute", 1, 2, None));
+
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.pessimistic_balance(DisputeMode::Debit), dec!(10));
+    assert_eq!(acc.optimistic_balance(DisputeMode::Debit), dec!(15));
+}
+
+#[test]
+fn balance_views_match_the_outcome_of_hold_only_disputes() {
+    let scenario = |outcome: &str| {
+        let mut engine: Engine = Engine::with_config(Config {
+            dispute_mode: DisputeMode::HoldOnly,
+            ..Config::default()
+        });
+        engine.apply(mk_row("deposit", 1, 1, Some("10")));
+        engine.apply(mk_row("dispute", 1, 1, None));
+        let acc = engine.accounts[&1].clone();
+        assert_eq!(acc.total(), dec!(20));
+        engine.apply(mk_row(outcome, 1, 1, None));
+        (acc, engine.accounts[&1].available)
+    };
+    let (acc, charged_back) = scenario("chargeback");
+    assert_eq!(acc.pessimistic_balance(DisputeMode::HoldOnly), charged_back);
+    assert_eq!(charged_back, dec!(0));
+    let (acc, resolved) = scenario("resolve");
+    assert_eq!(acc.optimistic_balance(DisputeMode::HoldOnly), resolved);
+    assert_eq!(resolved, dec!(10));
+}
+
+#[test]
+fn process_reader_collects_type_histogram() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+Deposit,1,2,5
+withdraw,1,3,1
+dispute,1,1,
+deposit,x,4,1
+";
+    let mut engine: Engine = Engine::default();
+    let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+    assert_eq!(stats.rows, 5);
+    assert_eq!(stats.malformed, 1);
+    assert_eq!(stats.rejected, 1);
+    assert_eq!(stats.types["deposit"], 2);
+    assert_eq!(stats.types["withdraw"], 1);
+    assert_eq!(stats.types["dispute"], 1);
+    assert_eq!(stats.types.len(), 3);
+}
+
+#[test]
+fn replayed_batch_is_not_applied_twice() {
+    let csv = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\nwithdrawal,1,3,2\n";
+    let rows = || {
+        csv_reader(csv.as_bytes())
+            .deserialize::<InputRow>()
+            .flatten()
+            .collect::<Vec<_>>()
+    };
+    let batch_id = content_batch_id(csv.as_bytes());
+
+    let mut engine: Engine = Engine::default();
+    assert!(engine.apply_batch(&batch_id, rows()));
+    assert!(!engine.apply_batch(&batch_id, rows()));
+    assert_eq!(engine.accounts[&1].available, dec!(13));
+
+    // a different batch is applied normally
+    let other = [mk_row("deposit", 1, 4, Some("1"))];
+    assert!(engine.apply_batch("manual-1", other));
+    assert_eq!(engine.accounts[&1].available, dec!(14));
+}
+
+#[test]
+fn content_batch_id_depends_on_content() {
+    assert_eq!(content_batch_id(b"abc"), content_batch_id(b"abc"));
+    assert_ne!(content_batch_id(b"abc"), content_batch_id(b"abd"));
+    assert_eq!(
+        content_batch_id(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+}
+
+fn locked_engine(config: Config) -> Engine {
+    let mut engine: Engine = Engine::with_config(config);
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    assert!(engine.accounts[&1].locked);
+    engine
+}
+
+#[test]
+fn locked_account_blocks_disputes_by_default() {
+    let mut engine = locked_engine(Config::default());
+    engine.apply(mk_row("dispute", 1, 2, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(5));
+    assert_eq!(acc.held, dec!(0));
+}
+
+#[test]
+fn allow_dispute_when_locked_permits_dispute_and_resolve_only() {
+    let mut engine = locked_engine(Config {
+        allow_dispute_when_locked: true,
+        ..Config::default()
+    });
+    engine.apply(mk_row("dispute", 1, 2, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(5));
+
+    engine.apply(mk_row("resolve", 1, 2, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(5));
+    assert_eq!(acc.held, dec!(0));
+
+    // money movement and chargebacks are still blocked
+    engine.apply(mk_row("deposit", 1, 3, Some("1")));
+    engine.apply(mk_row("withdrawal", 1, 4, Some("1")));
+    engine.apply(mk_row("dispute", 1, 2, None));
+    engine.apply(mk_row("chargeback", 1, 2, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(5));
+    assert!(acc.locked);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_enters_an_apply_span_per_row() {
+    use std::sync::{Arc, Mutex};
+    use tracing::span;
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+    #[derive(Clone, Default)]
+    struct SpanCounter(Arc<Mutex<HashMap<String, u64>>>);
+
+    impl<S> Layer<S> for SpanCounter
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+            if let Some(span) = ctx.span(id) {
+                *self
+                    .0
+                    .lock()
+                    .unwrap()
+                    .entry(span.name().to_string())
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    let counter = SpanCounter::default();
+    let subscriber = tracing_subscriber::registry().with(counter.clone());
+    let csv = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\nwithdrawal,1,3,1\n";
+    tracing::subscriber::with_default(subscriber, || {
+        let mut engine: Engine = Engine::default();
+        process_reader(csv.as_bytes(), &mut engine).unwrap();
+    });
+
+    let counts = counter.0.lock().unwrap();
+    assert_eq!(counts["process"], 1);
+    assert_eq!(counts["apply"], 3);
+}
+
+fn dispute_mode_engine(mode: DisputeMode) -> Engine {
+    let mut engine: Engine = Engine::with_config(Config {
+        dispute_mode: mode,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("4")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine
+}
+
+#[test]
+fn debit_dispute_moves_available_to_held() {
+    let mut engine = dispute_mode_engine(DisputeMode::Debit);
+    let acc = &engine.accounts[&1];
+    assert_eq!((acc.available, acc.held), (dec!(4), dec!(10)));
+
+    engine.apply(mk_row("resolve", 1, 1, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!((acc.available, acc.held), (dec!(14), dec!(0)));
+}
+
+#[test]
+fn hold_only_dispute_leaves_available_untouched() {
+    let mut engine = dispute_mode_engine(DisputeMode::HoldOnly);
+    let acc = &engine.accounts[&1];
+    assert_eq!((acc.available, acc.held), (dec!(14), dec!(10)));
+
+    engine.apply(mk_row("resolve", 1, 1, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!((acc.available, acc.held), (dec!(14), dec!(0)));
+
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!((acc.available, acc.held), (dec!(4), dec!(0)));
+    assert!(acc.locked);
+}
+
+#[test]
+fn output_rounds_half_away_from_zero_and_totals_add_up() {
+    let row = |available, held| {
+        OutputRow::from_account(1, &Account::new(available, held, false), None, 2)
+    };
+    // banker's rounding would give 1.22 for the first
+    assert_eq!(row(dec!(1.225), dec!(0)).available, dec!(1.23));
+    assert_eq!(row(dec!(1.235), dec!(0)).available, dec!(1.24));
+    assert_eq!(row(dec!(-1.225), dec!(0)).available, dec!(-1.23));
+
+    // each part rounds down, the printed total is their sum
+    let r = row(dec!(0.004), dec!(0.004));
+    assert_eq!((r.available, r.held, r.total), (dec!(0), dec!(0), dec!(0)));
+    let r = row(dec!(0.005), dec!(0.005));
+    assert_eq!(r.total, r.available + r.held);
+}
+
+#[test]
+fn output_rows_serialize_with_optional_columns() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("1.5")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+
+    let acc = &engine.accounts[&1];
+    let mut out = Vec::new();
+    write_rows(
+        &mut out,
+        &OutputRow::header(false, false),
+        [OutputRow::from_account(1, acc, None, DEFAULT_SCALE)],
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n1,0.0,1.5,1.5,false\n"
+    );
+
+    let mut out = Vec::new();
+    let rows = [
+        OutputRow::from_account(1, acc, Some(DisputeMode::Debit), DEFAULT_SCALE),
+        OutputRow::grand_total(
+            engine.accounts.values(),
+            Some(DisputeMode::Debit),
+            DEFAULT_SCALE,
+        ),
+    ];
+    write_rows(&mut out, &OutputRow::header(true, false), rows).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked,pessimistic,optimistic\n\
+         1,0.0,1.5,1.5,false,0.0,1.5\n\
+         TOTAL,0.0,1.5,1.5,,0.0,1.5\n"
+    );
+}
+
+#[test]
+fn string_columns_with_commas_and_quotes_round_trip() {
+    #[derive(Debug, PartialEq, serde::Serialize, Deserialize)]
+    struct LockedRow {
+        client: ClientID,
+        locked: bool,
+        lock_reason: String,
+    }
+
+    let row = LockedRow {
+        client: 7,
+        locked: true,
+        lock_reason: "chargeback, tx 12 (\"fraud\")".to_string(),
+    };
+    let mut out = Vec::new();
+    write_rows(&mut out, &["client", "locked", "lock_reason"], [&row]).unwrap();
+
+    let parsed: Vec<LockedRow> = csv::Reader::from_reader(out.as_slice())
+        .deserialize()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(parsed, vec![row]);
+}
+
+#[test]
+fn since_and_until_bounds_are_inclusive() {
+    let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,1,2024-01-01T00:00:00Z
+deposit,1,2,2,2024-01-02T00:00:00Z
+deposit,1,3,4,2024-01-02T12:00:00+02:00
+deposit,1,4,8,2024-01-03T00:00:00Z
+deposit,1,5,16,2024-01-03T00:00:01Z
+deposit,1,6,32,
+deposit,1,7,64,yesterday
+";
+    let window = |exclude_untimestamped| Config {
+        since: DateTime::parse_from_rfc3339("2024-01-02T00:00:00Z").ok(),
+        until: DateTime::parse_from_rfc3339("2024-01-03T00:00:00Z").ok(),
+        exclude_untimestamped,
+        ..Config::default()
+    };
+
+    let mut engine: Engine = Engine::with_config(window(false));
+    let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+    assert_eq!(engine.accounts[&1].available, dec!(46));
+    assert_eq!(stats.malformed, 1);
+
+    let mut engine: Engine = Engine::with_config(window(true));
+    process_reader(csv.as_bytes(), &mut engine).unwrap();
+    assert_eq!(engine.accounts[&1].available, dec!(14));
+}
+
+fn snapshot_scenario() -> Engine {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10.25")));
+    engine.apply(mk_row("deposit", 2, 2, Some("5")));
+    engine.apply(mk_row("withdrawal", 1, 3, Some("0.0001")));
+    engine.apply(mk_row("dispute", 2, 2, None));
+    engine.apply(mk_row("deposit", 3, 4, Some("1")));
+    engine.apply(mk_row("dispute", 3, 4, None));
+    engine.apply(mk_row("chargeback", 3, 4, None));
+    engine
+}
+
+fn assert_same_state(a: &Engine, b: &Engine) {
+    let mut clients: Vec<_> = a.accounts.keys().copied().collect();
+    clients.sort();
+    let mut restored: Vec<_> = b.accounts.keys().copied().collect();
+    restored.sort();
+    assert_eq!(clients, restored);
+    for c in clients {
+        let (x, y) = (&a.accounts[&c], &b.accounts[&c]);
+        assert_eq!(
+            (x.available, x.held, x.locked, x.last_activity),
+            (y.available, y.held, y.locked, y.last_activity)
+        );
+    }
+    assert_eq!(a.transactions.len(), b.transactions.len());
+    for (id, t) in &a.transactions {
+        let r = &b.transactions[id];
+        assert_eq!(
+            (t.client_id, t.kind, t.amount, t.disputed),
+            (r.client_id, r.kind, r.amount, r.disputed)
+        );
+    }
+}
+
+#[test]
+fn json_snapshot_round_trip_restores_state() {
+    let engine = snapshot_scenario();
+    let mut buf = Vec::new();
+    engine.save_snapshot(&mut buf).unwrap();
+    let mut restored: Engine = Engine::load_snapshot(buf.as_slice(), Config::default()).unwrap();
+    assert_same_state(&engine, &restored);
+
+    // restored transactions are live
+    restored.apply(mk_row("resolve", 2, 2, None));
+    assert_eq!(restored.accounts[&2].available, dec!(5));
+    restored.apply(mk_row("deposit", 1, 1, Some("1")));
+    assert_eq!(restored.accounts[&1].available, dec!(10.2499));
+}
+
+#[test]
+fn snapshot_with_unknown_version_is_rejected() {
+    let json =
+        r#"{"version":99,"accounts":{},"transactions":{},"activity":0,"applied_batches":[]}"#;
+    let result = Engine::<Currency>::load_snapshot(json.as_bytes(), Config::default());
+    assert!(matches!(result, Err(SnapshotError::UnsupportedVersion(99))));
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn bincode_and_json_snapshots_restore_identical_state() {
+    let engine = snapshot_scenario();
+    let mut json = Vec::new();
+    engine.save_snapshot(&mut json).unwrap();
+    let mut bin = Vec::new();
+    engine.save_snapshot_bin(&mut bin).unwrap();
+    assert!(bin.len() < json.len());
+
+    let from_json: Engine = Engine::load_snapshot(json.as_slice(), Config::default()).unwrap();
+    let from_bin: Engine = Engine::load_snapshot_bin(bin.as_slice(), Config::default()).unwrap();
+    assert_same_state(&engine, &from_bin);
+    assert_same_state(&from_json, &from_bin);
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn bincode_snapshot_keeps_newer_fields_and_rejects_older_versions() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(InputRow {
+        currency: Some("EUR".to_string()),
+        ..mk_row("deposit", 1, 1, Some("10"))
+    });
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    let mut bin = Vec::new();
+    engine.save_snapshot_bin(&mut bin).unwrap();
+
+    let restored: Engine = Engine::load_snapshot_bin(bin.as_slice(), Config::default()).unwrap();
+    let account = &restored.accounts[&1];
+    assert_eq!(account.disputes, 1);
+    assert_eq!(account.currency(), Some("EUR"));
+    assert!(restored.transactions[&1].charged_back);
+
+    // version 2 lacked those fields, it is refused before being decoded
+    let old = bincode::serde::encode_to_vec((2u32, 0u8), bincode::config::standard()).unwrap();
+    let result = Engine::<Currency>::load_snapshot_bin(old.as_slice(), Config::default());
+    assert!(matches!(result, Err(SnapshotError::UnsupportedVersion(2))));
+}
+
+#[test]
+fn streaming_callback_sees_each_accepted_row() {
+    // the unknown type is rejected, the overdrawing withdrawal takes no effect
+    let csv = "type,client,tx,amount\ndeposit,1,1,10\nbonus,1,2,50\ndeposit,2,3,5\n\
+               withdrawal,2,5,100\nwithdrawal,1,4,4\n";
+    let mut engine: Engine = Engine::default();
+    let mut seen = Vec::new();
+    process_reader_streaming(csv.as_bytes(), &mut engine, None, |client, acc| {
+        seen.push((client, acc.available))
+    })
+    .unwrap();
+    assert_eq!(seen, vec![(1, dec!(10)), (2, dec!(5)), (1, dec!(6))]);
+}
+
+#[test]
+fn rate_limiter_throttles_callbacks_per_client() {
+    use std::time::{Duration, Instant};
+
+    let csv = "type,client,tx,amount\n\
+               deposit,1,1,1\ndeposit,1,2,1\ndeposit,1,3,1\ndeposit,1,4,1\n\
+               deposit,2,5,1\n";
+    let mut limited: Engine = Engine::default();
+    let mut limiter = RateLimiter::new(10);
+    let mut times = Vec::new();
+    let start = Instant::now();
+    process_reader_streaming(
+        csv.as_bytes(),
+        &mut limited,
+        Some(&mut limiter),
+        |client, _| times.push((client, start.elapsed())),
+    )
+    .unwrap();
+
+    // four callbacks for client 1 at 10/s take at least three 100ms intervals
+    let client_1: Vec<Duration> = times.iter().filter(|t| t.0 == 1).map(|t| t.1).collect();
+    for pair in client_1.windows(2) {
+        assert!(
+            pair[1] - pair[0] >= Duration::from_millis(95),
+            "{:?}",
+            client_1
+        );
+    }
+    assert!(
+        client_1[3] - client_1[0] >= Duration::from_millis(290),
+        "{:?}",
+        client_1
+    );
+    assert!(client_1[3] < Duration::from_secs(1), "{:?}", client_1);
+
+    // client 2 has its own budget and is not delayed further
+    let client_2 = times.iter().find(|t| t.0 == 2).unwrap().1;
+    assert!(client_2 - client_1[3] < Duration::from_millis(40));
+
+    // throttling does not change the computed state
+    let mut unlimited: Engine = Engine::default();
+    process_reader(csv.as_bytes(), &mut unlimited).unwrap();
+    assert_eq!(
+        limited.accounts[&1].available,
+        unlimited.accounts[&1].available
+    );
+    assert_eq!(limited.accounts[&1].available, dec!(4));
+}
+
+#[test]
+fn as_of_tx_stops_after_that_transaction() {
+    // ids are not in input order, so stopping at 3 must not mean "ids up to 3"
+    let csv = "\
+type,client,tx,amount
+deposit,1,7,10
+deposit,1,2,5
+deposit,2,3,1
+withdrawal,1,1,4
+deposit,2,9,100
+";
+    let mut engine: Engine = Engine::with_config(Config {
+        as_of_tx: Some(3),
+        ..Config::default()
+    });
+    let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+    assert_eq!(stats.rows, 3);
+    assert_eq!(engine.accounts[&1].available, dec!(15));
+    assert_eq!(engine.accounts[&2].available, dec!(1));
+}
+
+#[test]
+fn early_dispute_is_dropped_by_default() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(10));
+    assert_eq!(acc.held, dec!(0));
+}
+
+#[test]
+fn early_dispute_is_replayed_when_its_deposit_arrives() {
+    let mut engine: Engine = Engine::with_config(Config {
+        pending_limit: Some(10),
+        ..Config::default()
+    });
+    engine.apply(mk_row("dispute", 1, 1, None));
+    assert!(engine.accounts.is_empty());
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(10));
+    assert!(engine.pending.is_empty());
+    assert_eq!(engine.pending_count(), 0);
+}
+
+#[test]
+fn merge_keeps_applied_batches_and_counters() {
+    let mut left: Engine = Engine::default();
+    assert!(left.apply_batch("a", [mk_row("deposit", 1, 1, Some("10"))]));
+    let mut right: Engine = Engine::default();
+    let rows = [
+        mk_row("deposit", 2, 2, Some("5")),
+        mk_row("withdrawal", 2, 3, Some("50")),
+    ];
+    assert!(right.apply_batch("b", rows.clone()));
+    left.merge(right).unwrap();
+
+    // a batch applied by either shard is not applied again
+    assert!(!left.apply_batch("a", [mk_row("deposit", 1, 4, Some("1"))]));
+    assert!(!left.apply_batch("b", rows));
+    assert_eq!(left.accounts[&2].available, dec!(5));
+    assert_eq!(left.ignored_count(), 1);
+
+    // later rows rank after every merged account
+    left.apply(mk_row("deposit", 1, 5, Some("1")));
+    assert!(left.accounts[&1].last_activity() > left.accounts[&2].last_activity());
+}
+
+#[test]
+fn parked_rows_survive_a_snapshot_and_a_merge() {
+    let config = Config {
+        pending_limit: Some(10),
+        ..Config::default()
+    };
+    let mut engine: Engine = Engine::with_config(config.clone());
+    engine.apply(mk_row("dispute", 1, 1, None));
+    assert_eq!(engine.pending_count(), 1);
+
+    let mut json = Vec::new();
+    engine.save_snapshot(&mut json).unwrap();
+    let restored: Engine = Engine::load_snapshot(json.as_slice(), config.clone()).unwrap();
+    assert_eq!(restored.pending_count(), 1);
+
+    // the deposit lands in another shard, the merge replays the dispute
+    let mut other: Engine = Engine::with_config(config);
+    other.apply(mk_row("deposit", 1, 1, Some("10")));
+    other.merge(restored).unwrap();
+    assert_eq!(other.pending_count(), 0);
+    assert_eq!(other.accounts[&1].held, dec!(10));
+}
+
+#[test]
+fn pending_buffer_drops_rows_over_the_limit() {
+    let mut engine: Engine = Engine::with_config(Config {
+        pending_limit: Some(1),
+        ..Config::default()
+    });
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("dispute", 1, 2, None));
+    assert_eq!(engine.pending_count(), 1);
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(5));
+    assert_eq!(acc.held, dec!(10));
+    // the slot freed by the replay takes the next early row
+    engine.apply(mk_row("dispute", 1, 3, None));
+    assert_eq!(engine.pending_count(), 1);
+}
+
+#[test]
+fn stray_dispute_family_rows_create_no_account() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    // unknown transaction, and a known one that belongs to another client
+    for ty in ["dispute", "resolve", "chargeback"] {
+        engine.apply(mk_row(ty, 2, 99, None));
+        engine.apply(mk_row(ty, 3, 1, None));
+    }
+    assert_eq!(engine.accounts.len(), 1);
+    assert!(engine.accounts.contains_key(&1));
+}
+
+#[test]
+fn vec_sink_collects_report_rows() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 2, 1, Some("3")));
+    engine.apply(mk_row("deposit", 1, 2, Some("4")));
+
+    let mut sink = VecSink::default();
+    let rows = [
+        OutputRow::from_account(1, &engine.accounts[&1], None, DEFAULT_SCALE),
+        OutputRow::from_account(2, &engine.accounts[&2], None, DEFAULT_SCALE),
+    ];
+    write_to_sink(&mut sink, rows.clone()).unwrap();
+    assert_eq!(sink.rows, rows);
+}
+
+#[test]
+fn csv_sink_writes_header_without_rows() {
+    let mut out = Vec::new();
+    let mut sink = CsvSink::new(&mut out, OutputRow::header(false, false));
+    write_to_sink(&mut sink, []).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n"
+    );
+}
+
+#[test]
+fn computed_zero_is_never_printed_negative() {
+    let acc = Account {
+        // negating a zero keeps the sign bit, so this displays as -0.0
+        available: -(dec!(1.5) - dec!(1.5)),
+        held: dec!(2.25) - dec!(2.25),
+        ..Account::default()
+    };
+    let mut out = Vec::new();
+    write_rows(
+        &mut out,
+        &OutputRow::header(false, false),
+        [OutputRow::from_account(1, &acc, None, DEFAULT_SCALE)],
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n1,0.0,0.00,0.00,false\n"
+    );
+}
+
+#[test]
+fn schema_version_mismatch_aborts_even_when_not_strict() {
+    let csv = "\
+schema_version,type,client,tx,amount
+3,deposit,1,1,10
+4,deposit,1,2,10
+";
+    let mut engine: Engine = Engine::with_config(Config {
+        require_schema_version: Some(3),
+        ..Config::default()
+    });
+    let result = process_reader(csv.as_bytes(), &mut engine);
+    assert_eq!(
+        result.unwrap_err(),
+        ApplyError::SchemaVersionMismatch {
+            expected: 3,
+            found: Some("4".to_string())
+        }
+    );
+    assert_eq!(engine.accounts[&1].available, dec!(10));
+}
+
+#[test]
+fn client_transactions_only_lists_that_client() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 3, Some("10")));
+    engine.apply(mk_row("deposit", 2, 2, Some("5")));
+    engine.apply(mk_row("withdrawal", 1, 1, Some("4")));
+    engine.apply(mk_row("dispute", 1, 3, None));
+
+    let listed: Vec<(TransactionID, Currency, bool)> = engine
+        .client_transactions(1)
+        .into_iter()
+        .map(|(id, t)| (id, t.amount(), t.is_disputed()))
+        .collect();
+    assert_eq!(listed, vec![(1, dec!(4), false), (3, dec!(10), true)]);
+    assert!(engine.client_transactions(3).is_empty());
+}
+
+#[test]
+fn rejected_withdrawal_for_unseen_client_creates_no_account() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("withdrawal", 1, 1, Some("5")));
+    assert!(engine.accounts.is_empty());
+    assert!(engine.transactions.is_empty());
+}
+
+#[test]
+fn input_scale_limits_accepted_decimal_places() {
+    assert_eq!(parse_amount_with_scale("1.239", 3).unwrap(), dec!(1.239));
+    assert!(parse_amount_with_scale("1.239", 2).is_err());
+    assert_eq!(
+        parse_amount_with_scale("1.234567", 6).unwrap(),
+        dec!(1.234567)
+    );
+
+    let mut engine: Engine = Engine::with_config(Config {
+        input_scale: Some(2),
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("1.25")));
+    engine.apply(mk_row("deposit", 1, 2, Some("1.255")));
+    assert_eq!(engine.accounts[&1].available, dec!(1.25));
+}
+
+#[test]
+fn duplicate_tx_with_different_amount_is_flagged() {
+    let mut engine: Engine = Engine::with_config(Config {
+        check_duplicate_amounts: true,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("1.5")));
+    // same amount at a different scale is a plain duplicate
+    assert_eq!(
+        engine.try_apply(mk_row("deposit", 1, 1, Some("1.5000"))),
+        Ok(())
+    );
+    assert_eq!(
+        engine.try_apply(mk_row("withdrawal", 1, 1, Some("2"))),
+        Err(ApplyError::DuplicateAmountMismatch { tx: 1 })
+    );
+    assert_eq!(engine.accounts[&1].available, dec!(1.5));
+
+    // without the check the duplicate is still dropped silently
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("1.5")));
+    assert_eq!(engine.try_apply(mk_row("deposit", 1, 1, Some("2"))), Ok(()));
+}
+
+#[test]
+fn validate_counts_all_invalid_rows_but_keeps_few_messages() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,-1
+deposit,1,2,0
+bogus,1,3,
+deposit,1,4,2
+";
+    let report = validate_reader(csv.as_bytes(), &Config::default(), 2);
+    assert_eq!(report.valid, 1);
+    assert_eq!(report.invalid, 3);
+    assert_eq!(report.errors.len(), 2);
+}
+
+#[test]
+fn validate_reports_an_unreadable_header() {
+    let csv = b"type,cl\xffient,tx,amount\ndeposit,1,1,2\n";
+    let report = validate_reader(&csv[..], &Config::default(), 2);
+    assert_eq!(report.valid, 0);
+    assert_eq!(report.invalid, 1);
+    assert!(
+        report.errors[0].starts_with("line 1: "),
+        "{:?}",
+        report.errors
+    );
+}
+
+fn chargeback_scenario(chargeback_mode: ChargebackMode, dispute_mode: DisputeMode) -> Account {
+    let mut engine: Engine = Engine::with_config(Config {
+        chargeback_mode,
+        dispute_mode,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    engine.accounts[&1].clone()
+}
+
+#[test]
+fn reverse_chargeback_removes_the_disputed_funds() {
+    for dispute_mode in [DisputeMode::Debit, DisputeMode::HoldOnly] {
+        let acc = chargeback_scenario(ChargebackMode::Reverse, dispute_mode);
+        assert_eq!(acc.available, dec!(5));
+        assert_eq!(acc.held, dec!(0));
+        assert!(acc.locked);
+    }
+}
+
+#[test]
+fn refund_chargeback_pays_the_disputed_funds_back() {
+    for dispute_mode in [DisputeMode::Debit, DisputeMode::HoldOnly] {
+        let acc = chargeback_scenario(ChargebackMode::Refund, dispute_mode);
+        assert_eq!(acc.available, dec!(15));
+        assert_eq!(acc.held, dec!(0));
+        assert!(acc.locked);
+    }
+}
+
+#[test]
+fn repeated_dispute_resolve_cycles_do_not_drift() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("0.0001")));
+    engine.apply(mk_row("deposit", 1, 2, Some("3.3333")));
+    engine.apply(mk_row("deposit", 1, 3, Some("1234.5679")));
+    let original = engine.accounts[&1].clone();
+
+    for _ in 0..1000 {
+        for tx in 1..=3 {
+            engine.apply(mk_row("dispute", 1, tx, None));
+        }
+        // resolve in a different order than disputed
+        for tx in [2, 3, 1] {
+            engine.apply(mk_row("resolve", 1, tx, None));
+        }
+    }
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, original.available);
+    assert_eq!(acc.held, Decimal::ZERO);
+    assert_eq!(acc.available, dec!(1237.9013));
+}
+
+#[test]
+fn account_filter_leaves_skipped_transactions_unrecorded() {
+    let mut engine: Engine = Engine::with_config(Config {
+        account_filter: Some(HashSet::from([1])),
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 2, 1, Some("10")));
+    // tx 1 was never recorded, so client 1 can still use it
+    engine.apply(mk_row("deposit", 1, 1, Some("5")));
+    assert_eq!(engine.accounts.len(), 1);
+    assert_eq!(engine.accounts[&1].available, dec!(5));
+}
+
+#[test]
+fn locked_and_disputed_counts() {
+    let mut engine: Engine = Engine::default();
+    assert_eq!(engine.locked_account_count(), 0);
+    assert_eq!(engine.disputed_transaction_count(), 0);
+
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 2, 2, Some("10")));
+    engine.apply(mk_row("deposit", 2, 3, Some("10")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    engine.apply(mk_row("dispute", 2, 2, None));
+
+    assert_eq!(engine.locked_account_count(), 1);
+    assert_eq!(engine.disputed_transaction_count(), 1);
+}
+
+#[test]
+fn amount_minor_is_converted_at_the_input_scale() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(InputRow {
+        amount_minor: Some("12345".to_string()),
+        ..mk_row("deposit", 1, 1, None)
+    });
+    engine.apply(InputRow {
+        amount_minor: Some("345".to_string()),
+        ..mk_row("withdrawal", 1, 2, None)
+    });
+    assert_eq!(engine.accounts[&1].available, dec!(1.2));
+
+    let mut engine: Engine = Engine::with_config(Config {
+        input_scale: Some(2),
+        ..Config::default()
+    });
+    engine.apply(InputRow {
+        amount_minor: Some("150".to_string()),
+        ..mk_row("deposit", 1, 1, None)
+    });
+    assert_eq!(engine.accounts[&1].available, dec!(1.5));
+}
+
+#[test]
+fn amount_and_amount_minor_together_are_rejected() {
+    let mut engine: Engine = Engine::default();
+    let result = engine.try_apply(InputRow {
+        amount_minor: Some("10000".to_string()),
+        ..mk_row("deposit", 1, 1, Some("1"))
+    });
+    assert_eq!(result, Err(ApplyError::InvalidAmount { tx: 1 }));
+    assert!(engine.accounts.is_empty());
+}
+
+#[test]
+fn caught_panic_rejects_the_row_and_processing_continues() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5
+deposit,1,3,1
+";
+    let mut engine: Engine = Engine::with_config(Config {
+        catch_panics: true,
+        ..Config::default()
+    });
+    engine.panic_on_tx = Some(2);
+    let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+    assert_eq!(stats.rejected, 1);
+    assert_eq!(engine.accounts[&1].available, dec!(11));
+
+    // strict mode reports the panic as the first error
+    let mut engine: Engine = Engine::with_config(Config {
+        catch_panics: true,
+        strict: true,
+        ..Config::default()
+    });
+    engine.panic_on_tx = Some(2);
+    let result = process_reader(csv.as_bytes(), &mut engine);
+    assert_eq!(result, Err(ApplyError::Panicked { tx: 2 }));
+}
+
+#[test]
+fn locked_account_accepts_deposits_when_allowed() {
+    let mut engine: Engine = Engine::with_config(Config {
+        locked_allows_deposit: true,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    assert!(engine.accounts[&1].locked);
+
+    engine.apply(mk_row("deposit", 1, 3, Some("7")));
+    engine.apply(mk_row("withdrawal", 1, 4, Some("1")));
+    engine.apply(mk_row("dispute", 1, 2, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(12));
+    assert_eq!(acc.held, dec!(0));
+    assert!(acc.locked);
+}
+
+#[test]
+fn locked_account_drops_deposits_by_default() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    engine.apply(mk_row("deposit", 1, 2, Some("7")));
+    assert_eq!(engine.accounts[&1].available, dec!(0));
+    assert!(!engine.transactions.contains_key(&2));
+}
+
+#[test]
+fn sniff_delimiter_prefers_comma_and_needs_expected_columns() {
+    assert_eq!(sniff_delimiter(b"type,client,tx,amount"), Some(b','));
+    assert_eq!(
+        sniff_delimiter(b"type | client | tx | amount\r"),
+        Some(b'|')
+    );
+    assert_eq!(sniff_delimiter(b"kind;client;tx"), None);
+}
+
+#[test]
+fn net_movement_ignores_disputes() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("withdrawal", 1, 3, Some("3")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.held, dec!(10));
+    assert_eq!(acc.net_movement, dec!(12));
+
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    assert_eq!(engine.accounts[&1].net_movement, dec!(12));
+}
+
+#[test]
+fn seeded_accounts_feed_accessors() {
+    let mut engine: Engine = Engine::default();
+    engine.insert_account(1, Account::new(dec!(5), dec!(2.5), false));
+    engine.insert_account(2, Account::new(dec!(-1), dec!(0), true));
+
+    assert_eq!(engine.accounts()[&1].total(), dec!(7.5));
+    assert_eq!(engine.locked_account_count(), 1);
+    // seeded accounts take part in processing like any other
+    engine.apply(mk_row("withdrawal", 1, 1, Some("5")));
+    assert_eq!(engine.accounts()[&1].available, dec!(0));
+}
+
+#[test]
+fn oversized_field_rejects_the_row() {
+    let huge = "1".repeat(1 << 20);
+    let csv = format!(
+        "type,client,tx,amount\ndeposit,1,1,{}\ndeposit,1,2,5\n",
+        huge
+    );
+    let mut engine: Engine = Engine::default();
+    let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+    assert_eq!(stats.rejected, 1);
+    assert_eq!(engine.accounts[&1].available, dec!(5));
+
+    let mut engine: Engine = Engine::with_config(Config {
+        max_field_len: Some(8),
+        strict: true,
+        ..Config::default()
+    });
+    let csv = "type,client,tx,amount\ndeposit,1,1,10000000\ndeposit,1,2,100000000\n";
+    let result = process_reader(csv.as_bytes(), &mut engine);
+    assert_eq!(result, Err(ApplyError::FieldTooLong { len: 9 }));
+    assert_eq!(engine.accounts[&1].available, dec!(10000000));
+}
+
+#[test]
+fn oversized_field_is_cut_while_reading() {
+    let huge = "1".repeat(1 << 20);
+    let csv = format!(
+        "type,client,tx,amount\ndeposit,1,1,{}\ndeposit,1,2,5\ndeposit,1,3,\"{}\n",
+        huge, huge
+    );
+    for fast_path in [false, true] {
+        let mut engine: Engine = Engine::with_config(Config {
+            max_field_len: Some(8),
+            fast_path,
+            ..Config::default()
+        });
+        let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+        // the field is never buffered past the limit, the next line is intact
+        assert_eq!(stats.rows, 3, "fast_path={}", fast_path);
+        assert_eq!(stats.rejected, 2, "fast_path={}", fast_path);
+        assert_eq!(engine.accounts[&1].available, dec!(5));
+    }
+
+    let mut engine: Engine = Engine::with_config(Config {
+        max_field_len: Some(8),
+        strict: true,
+        ..Config::default()
+    });
+    let result = process_reader(csv.as_bytes(), &mut engine);
+    assert_eq!(result, Err(ApplyError::FieldTooLong { len: 9 }));
+}
+
+#[test]
+fn quoted_delimiters_and_long_lines_are_cut_while_reading() {
+    let mut quoted = String::new();
+    while quoted.len() < 1 << 20 {
+        quoted.push_str(&"1".repeat(99));
+        quoted.push(',');
+    }
+    let many_fields = ",1".repeat(1 << 19);
+    let csv = format!(
+        "type,client,tx,amount\ndeposit,1,1,\"{}\"\ndeposit,1,2,5{}\ndeposit,1,3,5\n",
+        quoted, many_fields
+    );
+    let mut engine: Engine = Engine::with_config(Config {
+        max_field_len: Some(8),
+        ..Config::default()
+    });
+    let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+    assert_eq!(stats.rows, 3);
+    assert_eq!(stats.rejected, 2);
+    assert_eq!(engine.accounts[&1].available, dec!(5));
+
+    // both are cut near the limits instead of being buffered whole
+    for line in [1, 2] {
+        let mut engine: Engine = Engine::with_config(Config {
+            max_field_len: Some(8),
+            strict: true,
+            ..Config::default()
+        });
+        let input: Vec<&str> = csv.lines().collect();
+        let single = format!("{}\n{}\n", input[0], input[line]);
+        match process_reader(single.as_bytes(), &mut engine) {
+            Err(ApplyError::FieldTooLong { len }) => assert!(len <= 8 * 32 + 4, "len={}", len),
+            other => panic!("line {}: {:?}", line, other),
+        }
+    }
+}
+
+#[test]
+fn resolve_and_chargeback_release_only_the_disputed_transaction() {
+    // disputes cover whole transactions, an amount on the row is ignored
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("4")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("dispute", 1, 2, None));
+    assert_eq!(engine.accounts[&1].held, dec!(14));
+
+    engine.apply(mk_row("resolve", 1, 2, Some("14")));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.held, dec!(10));
+    assert_eq!(acc.available, dec!(4));
+
+    // a second resolve or a chargeback on the settled tx finds nothing disputed
+    engine.apply(mk_row("resolve", 1, 2, None));
+    engine.apply(mk_row("chargeback", 1, 2, None));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.held, dec!(10));
+    assert!(!acc.locked);
+
+    engine.apply(mk_row("chargeback", 1, 1, Some("100")));
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.held, dec!(0));
+    assert_eq!(acc.available, dec!(4));
+    assert!(acc.locked);
+}
+
+#[test]
+fn apply_all_applies_rows_in_order() {
+    let mut engine: Engine = Engine::default();
+    engine.apply_all(vec![
+        mk_row("deposit", 1, 1, Some("10")),
+        mk_row("withdrawal", 1, 2, Some("4")),
+        mk_row("withdrawal", 1, 3, Some("7")),
+        mk_row("deposit", 2, 4, Some("1.5")),
+    ]);
+    assert_eq!(engine.accounts[&1].available, dec!(6));
+    assert_eq!(engine.accounts[&2].available, dec!(1.5));
+}
+
+#[test]
+fn apply_all_commands_matches_equivalent_rows() {
+    let mut engine: Engine = Engine::default();
+    engine.apply_all_commands([
+        Command::Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10.25),
+        },
+        Command::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(0.25),
+        },
+        Command::Dispute { client: 1, tx: 1 },
+        Command::Void { client: 1, tx: 2 },
+    ]);
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(10.25));
+    assert!(!engine.transactions.contains_key(&2));
+}
+
+#[test]
+fn snapshots_of_the_same_input_are_byte_identical() {
+    let csv = "\
+type,client,tx,amount
+deposit,9,9,1
+deposit,3,3,2
+deposit,7,7,3
+deposit,1,1,4
+dispute,3,3,
+withdrawal,7,8,1
+";
+    let snapshot = || {
+        let mut engine: Engine = Engine::default();
+        process_reader(csv.as_bytes(), &mut engine).unwrap();
+        engine.apply_batch("b", []);
+        engine.apply_batch("a", []);
+        let mut out = Vec::new();
+        engine.save_snapshot(&mut out).unwrap();
+        out
+    };
+    assert_eq!(snapshot(), snapshot());
+}
+
+#[test]
+fn dispute_requires_funds_rejects_withdrawn_deposit() {
+    let mut engine: Engine = Engine::with_config(Config {
+        dispute_requires_funds: true,
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("100")));
+    engine.apply(mk_row("withdrawal", 1, 2, Some("100")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(0));
+    assert!(!engine.transactions[&1].disputed);
+
+    // a covered dispute still goes through
+    engine.apply(mk_row("deposit", 1, 3, Some("10")));
+    engine.apply(mk_row("dispute", 1, 3, None));
+    assert_eq!(engine.accounts[&1].held, dec!(10));
+}
+
+#[test]
+fn account_template_seeds_new_clients() {
+    let mut engine: Engine = Engine::default().with_account_template(Account {
+        available: dec!(10),
+        ..Account::default()
+    });
+    engine.apply(mk_row("withdrawal", 1, 1, Some("5")));
+    engine.apply(mk_row("deposit", 2, 2, Some("1")));
+
+    assert_eq!(engine.accounts[&1].available, dec!(5));
+    assert_eq!(engine.accounts[&2].available, dec!(11));
+    // no row, no account: the credit only applies once a client transacts
+    assert!(!engine.accounts.contains_key(&3));
+}
+
+#[test]
+fn transaction_accessors_after_processing() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("3")));
+    engine.apply(mk_row("withdrawal", 1, 2, Some("1")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+
+    let txs = engine.transactions();
+    let (_, deposit) = txs[0];
+    assert_eq!(deposit.kind(), TransactionKind::Deposit);
+    assert_eq!(deposit.client_id(), 1);
+    assert_eq!(deposit.amount(), dec!(3));
+    assert!(deposit.is_disputed());
+
+    let (_, withdrawal) = txs[1];
+    assert_eq!(withdrawal.kind(), TransactionKind::Withdrawal);
+    assert_eq!(withdrawal.kind().as_str(), "withdrawal");
+    assert_eq!(withdrawal.amount(), dec!(1));
+    assert!(!withdrawal.is_disputed());
+}
+
+#[test]
+fn dispute_family_ignores_missing_or_empty_amount() {
+    for amount in [None, Some("")] {
+        let mut engine: Engine = Engine::default();
+        engine.apply(mk_row("deposit", 1, 1, Some("10")));
+        engine.apply(mk_row("deposit", 1, 2, Some("4")));
+
+        engine.apply(mk_row("dispute", 1, 1, amount));
+        assert_eq!(engine.accounts[&1].held, dec!(10), "amount={:?}", amount);
+        engine.apply(mk_row("resolve", 1, 1, amount));
+        assert_eq!(
+            engine.accounts[&1].available,
+            dec!(14),
+            "amount={:?}",
+            amount
+        );
+
+        engine.apply(mk_row("dispute", 1, 2, amount));
+        engine.apply(mk_row("chargeback", 1, 2, amount));
+        let account = &engine.accounts[&1];
+        assert_eq!(account.available, dec!(10), "amount={:?}", amount);
+        assert_eq!(account.held, dec!(0), "amount={:?}", amount);
+        assert!(account.locked, "amount={:?}", amount);
+    }
+}
+
+// credits the row's amount, without recording a disputable transaction
+struct Bonus;
+
+impl TransactionHandler for Bonus {
+    fn handle(&self, engine: &mut Engine, row: &InputRow) -> Result<(), ApplyError> {
+        let amount: Currency = parse_input_amount(
+            row.amount.as_deref(),
+            DEFAULT_SCALE,
+            PrecisionPolicy::Reject,
+        )
+        .ok_or(ApplyError::InvalidAmount {
+            tx: row.transaction_id,
+        })?;
+        engine.account_mut(row.client_id).available += amount;
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_handler_applies_unknown_types() {
+    let mut engine: Engine = Engine::default();
+    engine.register_handler("Bonus", Bonus);
+    // built-in types are not overridden
+    engine.register_handler("deposit", Bonus);
+
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("bonus", 1, 2, Some("2.5")));
+    assert_eq!(engine.accounts[&1].available, dec!(12.5));
+    assert_eq!(engine.transactions().len(), 1);
+
+    assert_eq!(
+        engine.try_apply(mk_row("bonus", 1, 3, None)),
+        Err(ApplyError::InvalidAmount { tx: 3 })
+    );
+    assert_eq!(
+        engine.try_apply(mk_row("promo", 1, 4, Some("1"))),
+        Err(ApplyError::UnknownTransaction {
+            ty: "promo".to_string()
+        })
+    );
+    assert_eq!(engine.accounts[&1].available, dec!(12.5));
+}
+
+#[test]
+fn strict_mode_reports_blank_client_and_tx() {
+    let empty_client = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,  ,2,5\n";
+    let empty_tx = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1, ,5\n";
+
+    for (csv, field) in [(empty_client, "client"), (empty_tx, "tx")] {
+        let mut lenient: Engine = Engine::default();
+        let stats = process_reader(csv.as_bytes(), &mut lenient).unwrap();
+        assert_eq!(stats.malformed, 1);
+        assert_eq!(lenient.accounts[&1].available, dec!(10));
+
+        let mut strict: Engine = Engine::with_config(Config {
+            strict: true,
+            ..Config::default()
+        });
+        assert_eq!(
+            process_reader(csv.as_bytes(), &mut strict),
+            Err(ApplyError::MissingField { field })
+        );
+    }
+}
+
+fn fast_path_engine(fast_path: bool, strict: bool) -> Engine {
+    Engine::with_config(Config {
+        fast_path,
+        strict,
+        pending_limit: Some(10),
+        ..Config::default()
+    })
+}
+
+type AccountState = (ClientID, Decimal, Decimal, bool, Decimal);
+type TransactionState = (TransactionID, ClientID, TransactionKind, Decimal, bool);
+
+fn engine_state(engine: &Engine) -> (Vec<AccountState>, Vec<TransactionState>) {
+    let accounts = engine
+        .accounts()
+        .iter()
+        .map(|(&c, a)| (c, a.available, a.held, a.locked, a.net_movement))
+        .collect();
+    let transactions = engine
+        .transactions()
+        .into_iter()
+        .map(|(tx, t)| (tx, t.client_id(), t.kind(), t.amount(), t.is_disputed()))
+        .collect();
+    (accounts, transactions)
+}
+
+#[test]
+fn fast_path_matches_regular_path() {
+    let csv = "\
+type,client,tx,amount,amount_minor
+deposit,1,1,1.0,
+deposit,2,2,2.0,
+deposit,1,3,2.0,
+withdrawal,1,4,1.5,
+withdrawal,2,5,3.0,
+ Deposit ,3,6,10,
+DEPOSIT,3,7,,25000
+dispute,3,7,,
+dispute,1,99,,
+deposit,1,99,0.5,
+resolve,1,99
+deposit,4,8,-1,
+deposit,4,9,abc,
+deposit,4,10,1,1
+withdrawal,3,11,100,
+bonus,3,12,1,
+deposit,70000,13,1,
+deposit,x,14,1,
+deposit,5,15,1.00005,
+chargeback,3,7,,
+deposit,3,16,1,
+deposit,1,1,7.0,
+";
+    let mut regular = fast_path_engine(false, false);
+    let mut fast = fast_path_engine(true, false);
+    assert!(fast.fast_path_applies());
+    let regular_stats = process_reader(csv.as_bytes(), &mut regular).unwrap();
+    let fast_stats = process_reader(csv.as_bytes(), &mut fast).unwrap();
+
+    assert_eq!(fast_stats, regular_stats);
+    assert_eq!(engine_state(&fast), engine_state(&regular));
+    // the sample exercises every outcome
+    assert_eq!(regular_stats.malformed, 1);
+    assert_eq!(regular_stats.rejected, 3);
+    assert!(regular.accounts[&3].locked);
+
+    // strict mode stops at the same row with the same error
+    for csv in [csv, "type,client,tx,amount\ndeposit,1,1,1\ndeposit, ,2,1\n"] {
+        let mut regular = fast_path_engine(false, true);
+        let mut fast = fast_path_engine(true, true);
+        let expected = process_reader(csv.as_bytes(), &mut regular);
+        assert!(expected.is_err());
+        assert_eq!(process_reader(csv.as_bytes(), &mut fast), expected);
+        assert_eq!(engine_state(&fast), engine_state(&regular));
+    }
+}
+
+#[test]
+fn fast_path_is_skipped_when_the_row_is_needed() {
+    let mut engine: Engine = Engine::with_config(Config {
+        fast_path: true,
+        dedup_consecutive_rows: true,
+        ..Config::default()
+    });
+    assert!(!engine.fast_path_applies());
+
+    engine = Engine::with_config(Config {
+        fast_path: true,
+        ..Config::default()
+    });
+    engine.register_handler("bonus", Bonus);
+    assert!(!engine.fast_path_applies());
+}
+
+#[test]
+fn recompute_held_repairs_mismatched_accounts() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("4")));
+    engine.apply(mk_row("deposit", 2, 3, Some("5")));
+    engine.apply(mk_row("deposit", 3, 4, Some("1")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("dispute", 1, 2, None));
+    engine.apply(mk_row("dispute", 3, 4, None));
+    // as if restored from a damaged snapshot
+    engine.accounts.get_mut(&1).unwrap().held = dec!(3);
+    engine.accounts.get_mut(&2).unwrap().held = dec!(0.5);
+
+    assert_eq!(
+        engine.recompute_held(),
+        vec![(1, dec!(3), dec!(14)), (2, dec!(0.5), dec!(0))]
+    );
+    assert_eq!(engine.accounts[&1].held, dec!(14));
+    assert_eq!(engine.accounts[&2].held, dec!(0));
+    assert_eq!(engine.accounts[&3].held, dec!(1));
+    assert_eq!(engine.accounts[&1].available, dec!(0));
+    assert!(engine.recompute_held().is_empty());
+}
+
+#[test]
+fn locked_total_under_each_chargeback_mode() {
+    let cases = [
+        (ChargebackMode::Reverse, DisputeMode::Debit, dec!(5)),
+        (ChargebackMode::Reverse, DisputeMode::HoldOnly, dec!(5)),
+        (ChargebackMode::Refund, DisputeMode::Debit, dec!(15)),
+        (ChargebackMode::Refund, DisputeMode::HoldOnly, dec!(15)),
+    ];
+    for (chargeback_mode, dispute_mode, total) in cases {
+        let mut engine: Engine = Engine::with_config(Config {
+            chargeback_mode,
+            dispute_mode,
+            ..Config::default()
+        });
+        engine.apply(mk_row("deposit", 1, 1, Some("10")));
+        engine.apply(mk_row("deposit", 1, 2, Some("5")));
+        engine.apply(mk_row("dispute", 1, 1, None));
+        engine.apply(mk_row("chargeback", 1, 1, None));
+
+        let account = &engine.accounts[&1];
+        let case = format!("{:?}/{:?}", chargeback_mode, dispute_mode);
+        assert!(account.locked, "{}", case);
+        assert_eq!(account.held, dec!(0), "{}", case);
+        assert_eq!(account.total(), total, "{}", case);
+        assert_eq!(
+            account.total(),
+            account.available + account.held,
+            "{}",
+            case
+        );
+    }
+}
+
+#[test]
+fn process_bytes_applies_the_sample_input() {
+    let data = b"\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,1,3,2.0
+withdrawal,1,4,1.5
+withdrawal,2,5,3.0
+";
+    let mut engine: Engine = Engine::default();
+    let stats = process_bytes(data, &mut engine).unwrap();
+
+    assert_eq!(stats.rows, 5);
+    assert_eq!(engine.accounts[&1].available, dec!(1.5));
+    assert_eq!(engine.accounts[&2].available, dec!(2.0));
+    assert_eq!(engine.accounts[&2].total(), dec!(2.0));
+}
+
+#[test]
+fn crlf_and_mixed_line_endings_parse_amounts() {
+    assert_eq!(parse_amount("1.0\r"), Ok(dec!(1.0)));
+    assert_eq!(parse_amount(" 2.5\r\n"), Ok(dec!(2.5)));
+
+    let csv = "type,client,tx,amount\r\n\
+               deposit,1,1,1.0\r\n\
+               deposit,1,2,2.5\n\
+               withdrawal,1,3,0.5\r\n\
+               dispute,1,2,\r\n\
+               deposit,2,4,3\r\n\
+               dispute,2,4\r\n";
+    for fast_path in [false, true] {
+        let mut engine: Engine = Engine::with_config(Config {
+            fast_path,
+            ..Config::default()
+        });
+        let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+        assert_eq!(stats.malformed, 0, "fast_path={}", fast_path);
+        assert_eq!(stats.rejected, 0, "fast_path={}", fast_path);
+
+        let client1 = &engine.accounts[&1];
+        assert_eq!(client1.available, dec!(0.5), "fast_path={}", fast_path);
+        assert_eq!(client1.held, dec!(2.5), "fast_path={}", fast_path);
+        assert_eq!(engine.accounts[&2].held, dec!(3), "fast_path={}", fast_path);
+    }
+}
+
+#[test]
+fn disputed_transactions_lists_only_open_disputes() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 2, 2, Some("4")));
+    engine.apply(mk_row("deposit", 2, 3, Some("1")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("dispute", 2, 2, None));
+    engine.apply(mk_row("resolve", 1, 1, None));
+
+    assert_eq!(engine.disputed_transactions(), vec![(2, 2, dec!(4))]);
+}
+
+#[test]
+fn table_sink_draws_bordered_right_aligned_table() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("3")));
+    engine.apply(mk_row("deposit", 12, 2, Some("1234.5")));
+
+    let mut out = Vec::new();
+    let mut sink = TableSink::new(&mut out, OutputRow::header(false, false));
+    let rows = [
+        OutputRow::from_account(1, &engine.accounts[&1], None, DEFAULT_SCALE),
+        OutputRow::from_account(12, &engine.accounts[&12], None, DEFAULT_SCALE),
+    ];
+    write_to_sink(&mut sink, rows).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
++--------+-----------+------+--------+--------+
+| client | available | held |  total | locked |
++--------+-----------+------+--------+--------+
+|      1 |         3 |    0 |      3 | false  |
+|     12 |    1234.5 |    0 | 1234.5 | false  |
++--------+-----------+------+--------+--------+
+"
+    );
+}
+
+#[test]
+fn rows_without_effect_are_counted_as_ignored() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,100
+deposit,1,3,-5
+dispute,1,1,
+dispute,1,1,
+chargeback,1,1,
+deposit,1,4,5
+dispute,2,9,
+reset,1,5,
+";
+    for fast_path in [false, true] {
+        let mut engine: Engine = Engine::with_config(Config {
+            fast_path,
+            ..Config::default()
+        });
+        let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+        // the withdrawal, the negative deposit, the second dispute, the
+        // deposit on the locked account, the unknown tx and the reset
+        assert_eq!(stats.ignored, 6, "fast_path={}", fast_path);
+        assert_eq!(engine.ignored_count(), 6, "fast_path={}", fast_path);
+        assert_eq!(stats.rejected, 0, "fast_path={}", fast_path);
+    }
+}
+
+#[test]
+fn open_provisions_accounts_with_optional_balance() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("open", 1, 1, None));
+    engine.apply(mk_row("open", 2, 2, Some("7.5")));
+    // opening twice keeps the existing account
+    engine.apply(mk_row("open", 2, 3, Some("100")));
+
+    assert_eq!(engine.accounts[&1].available, dec!(0));
+    assert_eq!(engine.accounts[&2].available, dec!(7.5));
+    assert_eq!(engine.ignored_count(), 1);
+    assert_eq!(
+        engine.try_apply(mk_row("open", 3, 4, Some("-1"))),
+        Err(ApplyError::InvalidAmount { tx: 4 })
+    );
+    assert!(!engine.accounts.contains_key(&3));
+}
+
+#[test]
+fn require_open_rejects_unopened_accounts() {
+    let config = Config {
+        require_open: true,
+        ..Config::default()
+    };
+    let mut engine: Engine = Engine::with_config(config.clone());
+    assert_eq!(
+        engine.try_apply(mk_row("deposit", 1, 1, Some("10"))),
+        Err(ApplyError::AccountNotOpen { client: 1 })
+    );
+    assert_eq!(
+        engine.try_apply(mk_row("withdrawal", 1, 2, Some("1"))),
+        Err(ApplyError::AccountNotOpen { client: 1 })
+    );
+    assert!(engine.accounts.is_empty());
+
+    engine.apply(mk_row("open", 1, 3, Some("2")));
+    assert_eq!(
+        engine.try_apply(mk_row("deposit", 1, 4, Some("10"))),
+        Ok(())
+    );
+    assert_eq!(
+        engine.try_apply(mk_row("withdrawal", 1, 5, Some("3"))),
+        Ok(())
+    );
+    assert_eq!(engine.accounts[&1].available, dec!(9));
+
+    // the same through the reader, on both parsing paths
+    let csv = "type,client,tx,amount\nopen,1,1,\ndeposit,1,2,5\ndeposit,2,3,5\n";
+    for fast_path in [false, true] {
+        let mut engine: Engine = Engine::with_config(Config {
+            fast_path,
+            ..config.clone()
+        });
+        let stats = process_reader(csv.as_bytes(), &mut engine).unwrap();
+        assert_eq!(stats.rejected, 1, "fast_path={}", fast_path);
+        assert_eq!(
+            engine.accounts[&1].available,
+            dec!(5),
+            "fast_path={}",
+            fast_path
+        );
+        assert!(!engine.accounts.contains_key(&2), "fast_path={}", fast_path);
+    }
+}
+
+#[test]
+fn state_dump_round_trips_with_config() {
+    let mut engine: Engine = Engine::with_config(Config {
+        dispute_mode: DisputeMode::HoldOnly,
+        max_client_id: Some(50),
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 2, 2, Some("4")));
+    engine.apply(mk_row("dispute", 2, 2, None));
+
+    let mut out = Vec::new();
+    engine.dump_state(&mut out).unwrap();
+
+    let document: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    let mut keys: Vec<&str> = document
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    keys.sort_unstable();
+    assert_eq!(keys, ["accounts", "config", "transactions", "version"]);
+
+    let dump: StateDump = serde_json::from_slice(&out).unwrap();
+    assert_eq!(dump.version, 1);
+    assert_eq!(dump.config.dispute_mode, DisputeMode::HoldOnly);
+    assert_eq!(dump.config.max_client_id, Some(50));
+    assert_eq!(dump.accounts[&1].available, dec!(10));
+    assert_eq!(dump.accounts[&2].held, dec!(4));
+    assert!(dump.transactions[&2].is_disputed());
+    assert_eq!(dump.transactions.len(), 2);
+}
+
+#[test]
+fn dispute_agents_can_dispute_other_clients_deposits() {
+    let mut engine: Engine = Engine::with_config(Config {
+        dispute_agents: HashSet::from([900]),
+        ..Config::default()
+    });
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("3")));
+    engine.apply(mk_row("withdrawal", 1, 3, Some("1")));
+
+    // a client that is not an agent still cannot
+    engine.apply(mk_row("dispute", 2, 1, None));
+    assert_eq!(engine.accounts[&1].held, dec!(0));
+
+    engine.apply(mk_row("dispute", 900, 1, None));
+    assert_eq!(engine.accounts[&1].held, dec!(10));
+    assert_eq!(engine.accounts[&1].available, dec!(2));
+    assert!(!engine.accounts.contains_key(&900));
+
+    // kind and dispute state are still checked
+    engine.apply(mk_row("dispute", 900, 1, None));
+    engine.apply(mk_row("dispute", 900, 3, None));
+    assert_eq!(engine.accounts[&1].held, dec!(10));
+    assert_eq!(engine.ignored_count(), 3);
+
+    // the owner settles it as usual
+    engine.apply(mk_row("resolve", 1, 1, None));
+    assert_eq!(engine.accounts[&1].available, dec!(12));
+}
+
+#[test]
+fn overdraft_limit_lets_withdrawals_go_negative_up_to_the_limit() {
+    let config = Config {
+        overdraft_limit: Some(dec!(10)),
+        ..Config::default()
+    };
+    let mut engine: Engine = Engine::with_config(config);
+    engine.apply(mk_row("deposit", 1, 1, Some("5")));
+
+    // within: 5 - 8 = -3
+    engine.apply(mk_row("withdrawal", 1, 2, Some("8")));
+    assert_eq!(engine.accounts[&1].available, dec!(-3));
+
+    // beyond: -3 - 7.0001 is past -10
+    engine.apply(mk_row("withdrawal", 1, 3, Some("7.0001")));
+    assert_eq!(engine.accounts[&1].available, dec!(-3));
+    assert!(!engine.transactions.contains_key(&3));
+
+    // at: -3 - 7 lands exactly on -10
+    engine.apply(mk_row("withdrawal", 1, 4, Some("7")));
+    assert_eq!(engine.accounts[&1].available, dec!(-10));
+    assert_eq!(engine.accounts[&1].total(), dec!(-10));
+    assert_eq!(engine.ignored_count(), 1);
+
+    // an unseen client can borrow too
+    engine.apply(mk_row("withdrawal", 2, 5, Some("10")));
+    assert_eq!(engine.accounts[&2].available, dec!(-10));
+}
+
+#[test]
+fn overdraft_limit_is_converted_to_minor_units() {
+    let mut engine = Engine::<Cents>::with_config(Config {
+        overdraft_limit: Some(dec!(1)),
+        ..Config::default()
+    });
+    // a limit of 1 is 100 cents, not 1
+    engine.apply(mk_row("withdrawal", 1, 1, Some("100")));
+    assert_eq!(engine.accounts[&1].available, Cents(-100));
+    engine.apply(mk_row("withdrawal", 1, 2, Some("1")));
+    assert_eq!(engine.accounts[&1].available, Cents(-100));
+}
+
+#[test]
+fn max_disputes_rejects_disputes_past_the_cap() {
+    let config = Config {
+        max_disputes: Some(1),
+        ..Config::default()
+    };
+    let mut engine: Engine = Engine::with_config(config);
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("deposit", 2, 3, Some("5")));
+
+    assert_eq!(engine.try_apply(mk_row("dispute", 1, 1, None)), Ok(()));
+    assert_eq!(
+        engine.try_apply(mk_row("dispute", 1, 2, None)),
+        Err(ApplyError::DisputeLimitExceeded { client: 1 })
+    );
+    assert_eq!(engine.accounts[&1].held, dec!(10));
+    assert!(!engine.transactions[&2].disputed);
+
+    // the cap is over the lifetime, resolving does not free a slot
+    engine.apply(mk_row("resolve", 1, 1, None));
+    assert!(engine.try_apply(mk_row("dispute", 1, 2, None)).is_err());
+
+    // other clients have their own count
+    assert_eq!(engine.try_apply(mk_row("dispute", 2, 3, None)), Ok(()));
+}
+
+#[test]
+fn void_undoes_a_deposit_and_frees_its_id() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("deposit", 1, 2, Some("5")));
+    engine.apply(mk_row("void", 1, 2, None));
+
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(10));
+    assert_eq!(acc.net_movement, dec!(10));
+    assert!(!engine.transactions.contains_key(&2));
+
+    // the id can be used again
+    engine.apply(mk_row("deposit", 1, 2, Some("3")));
+    assert_eq!(engine.accounts[&1].available, dec!(13));
+
+    // a withdrawal is paid back
+    engine.apply(mk_row("withdrawal", 1, 3, Some("4")));
+    engine.apply(mk_row("void", 1, 3, None));
+    assert_eq!(engine.accounts[&1].available, dec!(13));
+    assert_eq!(engine.ignored_count(), 0);
+}
+
+#[test]
+fn void_ignores_disputed_and_charged_back_transactions() {
+    let mut engine: Engine = Engine::default();
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("void", 1, 1, None));
+
+    let acc = &engine.accounts[&1];
+    assert_eq!(acc.available, dec!(0));
+    assert_eq!(acc.held, dec!(10));
+    assert!(engine.transactions.contains_key(&1));
+    assert_eq!(engine.ignored_count(), 1);
+
+    // still refused once a reset has unlocked the account again
+    let config = Config {
+        allow_admin_ops: true,
+        ..Config::default()
+    };
+    let mut engine: Engine = Engine::with_config(config);
+    engine.apply(mk_row("deposit", 1, 1, Some("10")));
+    engine.apply(mk_row("dispute", 1, 1, None));
+    engine.apply(mk_row("chargeback", 1, 1, None));
+    engine.apply(mk_row("reset", 1, 2, None));
+    engine.apply(mk_row("void", 1, 1, None));
+    assert!(engine.transactions.contains_key(&1));
+    assert_eq!(engine.ignored_count(), 1);
+}
+
+#[test]
+fn currency_of_the_first_row_is_kept_for_the_account() {
+    let in_currency = |row: InputRow, currency: &str| InputRow {
+        currency: Some(currency.to_string()),
+        ..row
+    };
+    let mut engine: Engine = Engine::default();
+    engine.apply(in_currency(mk_row("deposit", 1, 1, Some("10")), "EUR"));
+    assert_eq!(engine.accounts[&1].currency(), Some("EUR"));
+
+    // matching, also spelled differently, and rows without one are fine
+    assert_eq!(
+        engine.try_apply(in_currency(mk_row("deposit", 1, 2, Some("5")), " eur ")),
+        Ok(())
+    );
+    assert_eq!(
+        engine.try_apply(mk_row("withdrawal", 1, 3, Some("1"))),
+        Ok(())
+    );
+    assert_eq!(engine.accounts[&1].available, dec!(14));
+
+    assert_eq!(
+        engine.try_apply(in_currency(mk_row("withdrawal", 1, 4, Some("1")), "USD")),
+        Err(ApplyError::CurrencyMismatch {
+            client: 1,
+            expected: "EUR".to_string(),
+            found: "USD".to_string(),
+        })
+    );
+    assert_eq!(engine.accounts[&1].available, dec!(14));
+    assert!(!engine.transactions.contains_key(&4));
+
+    // a client's first row without a currency leaves it open
+    engine.apply(mk_row("deposit", 2, 5, Some("1")));
+    engine.apply(in_currency(mk_row("deposit", 2, 6, Some("1")), "USD"));
+    assert_eq!(engine.accounts[&2].currency(), Some("USD"));
+
+    // a row without effect does not decide it
+    engine.apply(mk_row("deposit", 3, 7, Some("1")));
+    engine.apply(in_currency(mk_row("withdrawal", 3, 8, Some("50")), "USD"));
+    engine.apply(in_currency(mk_row("dispute", 3, 99, None), "USD"));
+    assert_eq!(engine.ignored_count(), 2);
+    assert_eq!(engine.accounts[&3].currency(), None);
+    engine.apply(in_currency(mk_row("deposit", 3, 9, Some("1")), "GBP"));
+    assert_eq!(engine.accounts[&3].currency(), Some("GBP"));
+}
@@ -0,0 +1,129 @@
+use crate::{
+    ApplyError, Config, Currency, DEFAULT_MAX_FIELD_LEN, Engine, InputRow, amount_field,
+    client_id_overflow, duplicate_header, input_reader, missing_field, open_amount,
+};
+use std::io;
+
+/// Outcome of [`validate_reader`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Rows that would be accepted by the parser.
+    pub valid: u64,
+    pub invalid: u64,
+    /// Messages for the first invalid rows, prefixed with their line number.
+    pub errors: Vec<String>,
+}
+
+/// Checks that every row of `reader` parses, has a known type and, for
+/// deposits and withdrawals, a valid amount. No account state is built, so
+/// rows that only fail against earlier rows (e.g. insufficient funds) pass.
+///
+/// At most `max_errors` messages are kept; all invalid rows are counted.
+pub fn validate_reader<R: io::BufRead>(
+    reader: R,
+    config: &Config,
+    max_errors: usize,
+) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    // only used for type normalization, nothing is applied to it
+    let engine: Engine = Engine::with_config(config.clone());
+    let mut reader = input_reader(reader, config);
+    // without a header no row can be checked, the input as a whole is invalid
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            report.invalid += 1;
+            report.errors.push(format!("line 1: {}", e));
+            return report;
+        }
+    };
+    // no row can be mapped reliably, so nothing else is checked
+    if let Some(name) = duplicate_header(&headers) {
+        report.invalid += 1;
+        report.errors.push(format!(
+            "line 1: {}",
+            ApplyError::DuplicateHeader {
+                name: name.to_string()
+            }
+        ));
+        return report;
+    }
+    for record in reader.records() {
+        let result = record.map_err(|e| e.to_string()).and_then(|record| {
+            let line = record.position().map_or(0, |p| p.line());
+            validate_record(&engine, &headers, &record).map_err(|e| format!("line {}: {}", line, e))
+        });
+        match result {
+            Ok(()) => report.valid += 1,
+            Err(message) => {
+                report.invalid += 1;
+                if report.errors.len() < max_errors {
+                    report.errors.push(message);
+                }
+            }
+        }
+    }
+    report
+}
+
+fn validate_record(
+    engine: &Engine,
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+) -> Result<(), String> {
+    let max_field_len = engine.config.max_field_len.unwrap_or(DEFAULT_MAX_FIELD_LEN);
+    if let Some(len) = record.iter().map(str::len).find(|&len| len > max_field_len) {
+        return Err(ApplyError::FieldTooLong { len }.to_string());
+    }
+    let row: InputRow = record
+        .deserialize(Some(headers))
+        .map_err(|e| match client_id_overflow(headers, record) {
+            Some(client) => ApplyError::ClientIdOutOfRange { client }.to_string(),
+            None => match missing_field(headers, record) {
+                Some(field) => ApplyError::MissingField { field }.to_string(),
+                None => e.to_string(),
+            },
+        })?;
+    if let Some(max) = engine.config.max_client_id
+        && row.client_id > max
+    {
+        return Err(ApplyError::ClientIdOutOfRange {
+            client: row.client_id.into(),
+        }
+        .to_string());
+    }
+    match engine.normalize_type(&row.transaction_type).as_str() {
+        "deposit" | "withdrawal" => {
+            let scale = engine.config.input_scale();
+            amount_field::<Currency>(
+                row.amount.as_deref(),
+                row.amount_minor.as_deref(),
+                row.transaction_id,
+                scale,
+                engine.config.precision,
+            )
+            .map_err(|e| e.to_string())?
+            .map(|_| ())
+            .ok_or_else(|| {
+                ApplyError::InvalidAmount {
+                    tx: row.transaction_id,
+                }
+                .to_string()
+            })
+        }
+        "open" => open_amount::<Currency>(
+            row.amount.as_deref(),
+            row.amount_minor.as_deref(),
+            row.transaction_id,
+            engine.config.input_scale(),
+            engine.config.precision,
+        )
+        .map(|_| ())
+        .map_err(|e| e.to_string()),
+        "dispute" | "resolve" | "chargeback" | "reset" | "void" => Ok(()),
+        _ => Err(ApplyError::UnknownTransaction {
+            ty: row.transaction_type.trim().to_string(),
+        }
+        .to_string()),
+    }
+}
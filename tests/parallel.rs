@@ -0,0 +1,136 @@
+use rust_decimal::Decimal;
+use std::thread;
+use transaction_processing::{
+    ClientID, CsvSink, Engine, InputRow, OutputRow, TransactionID, write_to_sink,
+    write_transactions,
+};
+
+const ROWS: usize = 50_000;
+const CLIENTS: u64 = 64;
+const SHARDS: u64 = 8;
+// fixed so a failure can be reproduced
+const SEED: u64 = 0x5eed_1234_abcd_0001;
+
+// xorshift64, good enough to mix the workload and fully reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+fn row(ty: &str, client: ClientID, tx: TransactionID, amount: Option<Decimal>) -> InputRow {
+    InputRow {
+        transaction_type: ty.to_string(),
+        client_id: client,
+        transaction_id: tx,
+        amount: amount.map(|a| a.to_string()),
+        ..InputRow::default()
+    }
+}
+
+// Deposits and withdrawals get fresh ids, so shards never share one. The
+// dispute family mostly targets the client's own earlier transactions and
+// sometimes another client's, which both runs must ignore the same way.
+fn workload() -> Vec<InputRow> {
+    let mut rng = Rng(SEED);
+    let mut issued: Vec<(TransactionID, ClientID)> = Vec::new();
+    let mut rows = Vec::with_capacity(ROWS);
+    for _ in 0..ROWS {
+        let client = rng.below(CLIENTS) as ClientID + 1;
+        let roll = rng.below(100);
+        if roll < 75 || issued.is_empty() {
+            let tx = issued.len() as TransactionID + 1;
+            issued.push((tx, client));
+            let amount = Decimal::new(rng.below(100_000) as i64 + 1, 4);
+            let ty = if roll < 50 { "deposit" } else { "withdrawal" };
+            rows.push(row(ty, client, tx, Some(amount)));
+            continue;
+        }
+        let (tx, owner) = issued[rng.below(issued.len() as u64) as usize];
+        let client = if rng.below(5) == 0 { client } else { owner };
+        let ty = match roll {
+            75..=87 => "dispute",
+            88..=94 => "resolve",
+            95..=97 => "chargeback",
+            _ => "void",
+        };
+        rows.push(row(ty, client, tx, None));
+    }
+    rows
+}
+
+fn shard_of(client: ClientID) -> usize {
+    (u64::from(client) % SHARDS) as usize
+}
+
+// the balance report and the transaction table, both in id order
+fn render(engine: &Engine) -> (String, String) {
+    let header = OutputRow::header(false, true);
+    let rows = engine.accounts().iter().map(|(&client, account)| {
+        OutputRow::from_account(client, account, None, 4).with_net_movement(account.net_movement, 4)
+    });
+    let mut report = Vec::new();
+    write_to_sink(&mut CsvSink::new(&mut report, header), rows).unwrap();
+    let mut transactions = Vec::new();
+    write_transactions(&mut transactions, engine).unwrap();
+    (
+        String::from_utf8(report).unwrap(),
+        String::from_utf8(transactions).unwrap(),
+    )
+}
+
+#[test]
+fn sharded_run_matches_serial_run() {
+    let rows = workload();
+
+    let mut serial: Engine = Engine::default();
+    serial.apply_all(rows.iter().cloned());
+
+    // each shard keeps its rows in input order
+    let mut shards: Vec<Vec<InputRow>> = vec![Vec::new(); SHARDS as usize];
+    for row in &rows {
+        shards[shard_of(row.client_id)].push(row.clone());
+    }
+    let engines: Vec<Engine> = thread::scope(|scope| {
+        let handles: Vec<_> = shards
+            .into_iter()
+            .map(|shard| {
+                scope.spawn(move || {
+                    let mut engine: Engine = Engine::default();
+                    engine.apply_all(shard);
+                    engine
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    // merged in reverse so the result cannot depend on shard order
+    let mut parallel: Engine = Engine::default();
+    for engine in engines.into_iter().rev() {
+        parallel.merge(engine).unwrap();
+    }
+
+    let (serial_report, serial_transactions) = render(&serial);
+    let (parallel_report, parallel_transactions) = render(&parallel);
+    // the workload reaches every client and every kind of outcome
+    assert_eq!(serial.accounts().len(), CLIENTS as usize);
+    assert!(serial.accounts().values().any(|a| a.locked));
+    assert!(!serial.disputed_transactions().is_empty());
+    assert!(serial.ignored_count() > 0);
+    assert_eq!(serial_report, parallel_report);
+    assert_eq!(serial_transactions, parallel_transactions);
+    assert_eq!(serial.ignored_count(), parallel.ignored_count());
+    assert_eq!(
+        serial.disputed_transactions(),
+        parallel.disputed_transactions()
+    );
+}
@@ -0,0 +1,151 @@
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use transaction_processing::{ClientID, Engine, InputRow, TransactionID};
+
+// small id spaces so generated sequences hit the same clients and transactions often
+const CLIENTS: ClientID = 3;
+const TRANSACTIONS: TransactionID = 12;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Deposit(ClientID, TransactionID, i64),
+    Withdrawal(ClientID, TransactionID, i64),
+    Dispute(ClientID, TransactionID),
+    Resolve(ClientID, TransactionID),
+    Chargeback(ClientID, TransactionID),
+}
+
+impl Op {
+    fn client(&self) -> ClientID {
+        match *self {
+            Op::Deposit(c, ..)
+            | Op::Withdrawal(c, ..)
+            | Op::Dispute(c, _)
+            | Op::Resolve(c, _)
+            | Op::Chargeback(c, _) => c,
+        }
+    }
+
+    fn to_row(&self) -> InputRow {
+        // amounts are generated in 1/100 units to keep shrinking simple
+        let (ty, client, tx, amount) = match *self {
+            Op::Deposit(c, t, a) => ("deposit", c, t, Some(Decimal::new(a, 2).to_string())),
+            Op::Withdrawal(c, t, a) => ("withdrawal", c, t, Some(Decimal::new(a, 2).to_string())),
+            Op::Dispute(c, t) => ("dispute", c, t, None),
+            Op::Resolve(c, t) => ("resolve", c, t, None),
+            Op::Chargeback(c, t) => ("chargeback", c, t, None),
+        };
+        InputRow {
+            transaction_type: ty.to_string(),
+            client_id: client,
+            transaction_id: tx,
+            amount,
+            ..InputRow::default()
+        }
+    }
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let client = 1..=CLIENTS;
+    let tx = 1..=TRANSACTIONS;
+    let amount = 1..10_000i64;
+    prop_oneof![
+        (client.clone(), tx.clone(), amount.clone()).prop_map(|(c, t, a)| Op::Deposit(c, t, a)),
+        (client.clone(), tx.clone(), amount).prop_map(|(c, t, a)| Op::Withdrawal(c, t, a)),
+        (client.clone(), tx.clone()).prop_map(|(c, t)| Op::Dispute(c, t)),
+        (client.clone(), tx.clone()).prop_map(|(c, t)| Op::Resolve(c, t)),
+        (client, tx).prop_map(|(c, t)| Op::Chargeback(c, t)),
+    ]
+}
+
+fn balances(engine: &Engine, client: ClientID) -> Option<(Decimal, Decimal, bool)> {
+    engine
+        .accounts()
+        .get(&client)
+        .map(|acc| (acc.available, acc.held, acc.locked))
+}
+
+proptest! {
+    #[test]
+    fn invariants_hold_for_random_sequences(ops in prop::collection::vec(op(), 1..60)) {
+        let mut engine: Engine = Engine::default();
+        // amount of every deposit that was accepted, to check chargebacks against
+        let mut deposits = std::collections::HashMap::new();
+
+        for op in &ops {
+            let client = op.client();
+            let before = balances(&engine, client);
+            engine.apply(op.to_row());
+            let after = balances(&engine, client);
+
+            let total = |b: Option<(Decimal, Decimal, bool)>| b.map(|(a, h, _)| a + h).unwrap_or_default();
+            let delta = total(after) - total(before);
+
+            if let Some((_, _, true)) = before {
+                prop_assert_eq!(before, after, "locked account changed on {:?}", op);
+            }
+            if let Some((_, held, _)) = after {
+                prop_assert!(held >= Decimal::ZERO, "negative held after {:?}", op);
+            }
+
+            match *op {
+                Op::Deposit(_, tx, a) => {
+                    let amount = Decimal::new(a, 2);
+                    prop_assert!(delta == Decimal::ZERO || delta == amount);
+                    if delta == amount {
+                        deposits.insert(tx, amount);
+                    }
+                }
+                Op::Withdrawal(_, _, a) => {
+                    prop_assert!(delta == Decimal::ZERO || delta == -Decimal::new(a, 2));
+                }
+                // disputes and resolves only move funds between available and held
+                Op::Dispute(..) | Op::Resolve(..) => prop_assert_eq!(delta, Decimal::ZERO),
+                Op::Chargeback(_, tx) => {
+                    if delta != Decimal::ZERO {
+                        prop_assert_eq!(Some(-delta), deposits.get(&tx).copied());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn row(ty: &str, tx: TransactionID, amount: Decimal) -> InputRow {
+    InputRow {
+        transaction_type: ty.to_string(),
+        client_id: 1,
+        transaction_id: tx,
+        amount: Some(amount.to_string()),
+        ..InputRow::default()
+    }
+}
+
+proptest! {
+    // Decimal accumulation must stay exact however many rows an account
+    // sees, reconciliation relies on balances summing back to zero
+    #[test]
+    fn deposits_and_matching_withdrawals_sum_to_exactly_zero(
+        amounts in prop::collection::vec(1..100_000i64, 1..2_000)
+    ) {
+        let mut engine: Engine = Engine::default();
+        let amounts: Vec<Decimal> = amounts.into_iter().map(|a| Decimal::new(a, 4)).collect();
+        let mut tx = 0;
+        for &amount in &amounts {
+            tx += 1;
+            engine.apply(row("deposit", tx, amount));
+        }
+        let expected: Decimal = amounts.iter().sum();
+        prop_assert_eq!(engine.accounts()[&1].available, expected);
+
+        // withdrawn in reverse so every one of them is covered
+        for &amount in amounts.iter().rev() {
+            tx += 1;
+            engine.apply(row("withdrawal", tx, amount));
+        }
+        let account = &engine.accounts()[&1];
+        prop_assert!(account.available.is_zero(), "drift: {}", account.available);
+        prop_assert!(account.total().is_zero());
+        prop_assert_eq!(engine.ignored_count(), 0);
+    }
+}
@@ -1,8 +1,12 @@
 use std::io::Write;
-use std::process::Command;
+use std::process::{Command, Output};
 use tempfile::NamedTempFile;
 
 fn run_with_csv(csv: &str) -> String {
+    run_with_args(csv, &[])
+}
+
+fn run_raw(csv: &str, args: &[&str]) -> Output {
     let bin = env!("CARGO_BIN_EXE_transaction_processing");
 
     let mut tmp = NamedTempFile::new().expect("create temp csv");
@@ -10,10 +14,19 @@ fn run_with_csv(csv: &str) -> String {
     let path = tmp.into_temp_path();
 
     let output = Command::new(bin)
+        .args(args)
         .arg(&path)
         .output()
         .expect("failed to execute binary");
 
+    // keep the temp file alive until the process has finished
+    let _ = path.close();
+    output
+}
+
+fn run_with_args(csv: &str, args: &[&str]) -> String {
+    let output = run_raw(csv, args);
+
     assert!(
         output.status.success(),
         "process failed: status={:?} stderr={}",
@@ -21,11 +34,7 @@ fn run_with_csv(csv: &str) -> String {
         String::from_utf8_lossy(&output.stderr)
     );
 
-    let stdout = String::from_utf8(output.stdout).expect("stdout not utf8");
-
-    // keep the temp file alive until after assertions
-    let _ = path.close();
-    stdout
+    String::from_utf8(output.stdout).expect("stdout not utf8")
 }
 
 #[test]
@@ -276,3 +285,1390 @@ client,available,held,total,locked
 ";
     assert_eq!(stdout, expected);
 }
+
+#[test]
+fn reset_is_ignored_without_admin_flag() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+reset,1,2,
+";
+    let stdout = run_with_csv(csv);
+    let expected = "\
+client,available,held,total,locked
+1,10,0,10,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn reset_clears_locked_account_with_admin_flag() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5
+dispute,1,1,
+chargeback,1,1,
+reset,1,3,
+deposit,1,4,2
+";
+    let stdout = run_with_args(csv, &["--allow-admin-ops"]);
+    let expected = "\
+client,available,held,total,locked
+1,2,0,2,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn strict_mode_fails_on_unknown_transaction_type() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+withdraw,1,2,5
+";
+    let output = run_raw(csv, &["--strict"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.trim_end(),
+        "error: unknown transaction type \"withdraw\"",
+        "stderr={}",
+        stderr
+    );
+}
+
+#[test]
+fn alias_option_maps_type_names() {
+    let csv = "\
+type,client,tx,amount
+dep,1,1,10
+wd,1,2,4
+";
+    let stdout = run_with_args(csv, &["--alias", "dep=deposit", "--alias", "wd=withdrawal"]);
+    let expected = "\
+client,available,held,total,locked
+1,6,0,6,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn normalize_types_maps_legacy_credit_and_debit() {
+    let csv = "\
+type,client,tx,amount
+credit,1,1,10
+Debit,1,2,4
+credit,2,3,1
+";
+    let expected = "\
+client,available,held,total,locked
+1,6,0,6,false
+2,1,0,1,false
+";
+    assert_eq!(run_with_args(csv, &["--normalize-types"]), expected);
+    // an explicit alias wins over the legacy mapping
+    let stdout = run_with_args(csv, &["--alias", "credit=withdrawal", "--normalize-types"]);
+    assert_eq!(stdout, "client,available,held,total,locked\n");
+
+    // without the flag they are unknown types
+    let output = run_raw(csv, &["--stats"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("rejected=3"));
+}
+
+#[test]
+fn tail_emits_most_recently_active_accounts() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,20
+deposit,3,3,30
+deposit,4,4,40
+withdrawal,2,5,5
+";
+    let stdout = run_with_args(csv, &["--tail", "2"]);
+    let expected = "\
+client,available,held,total,locked
+2,15,0,15,false
+4,40,0,40,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn tail_counts_a_void_as_activity() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,20
+deposit,3,3,30
+void,1,1,
+";
+    let stdout = run_with_args(csv, &["--tail", "2"]);
+    let expected = "\
+client,available,held,total,locked
+1,0,0,0,false
+3,30,0,30,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn balance_views_add_output_columns() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5
+dispute,1,2,
+deposit,2,3,1
+";
+    let stdout = run_with_args(csv, &["--balance-views"]);
+    let expected = "\
+client,available,held,total,locked,pessimistic,optimistic
+1,10,5,15,false,10,15
+2,1,0,1,false,1,1
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn balance_views_follow_the_dispute_mode() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+";
+    let args = [
+        "--balance-views",
+        "--dispute-mode",
+        "hold-only",
+        "--grand-total",
+    ];
+    let stdout = run_with_args(csv, &args);
+    let expected = "\
+client,available,held,total,locked,pessimistic,optimistic
+1,10,10,20,false,0,10
+TOTAL,10,10,20,,0,10
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn read_buffer_size_does_not_change_output() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,1,3,2.0
+withdrawal,1,4,1.5
+withdrawal,2,5,3.0
+";
+    let expected = run_with_csv(csv);
+    for bytes in ["1", "7", "4096"] {
+        let stdout = run_with_args(csv, &["--read-buffer-bytes", bytes]);
+        assert_eq!(stdout, expected, "buffer size {}", bytes);
+    }
+}
+
+#[test]
+fn stats_prints_type_histogram_to_stderr() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+withdrawal,1,3,1
+dispute,1,1,
+bonus,1,4,3
+";
+    let output = run_raw(csv, &["--stats"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).expect("stderr not utf8");
+    let histogram: Vec<(&str, u64)> = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("type."))
+        .filter_map(|line| line.split_once('='))
+        .map(|(ty, count)| (ty, count.parse().expect("count")))
+        .collect();
+    assert_eq!(
+        histogram,
+        vec![
+            ("bonus", 1),
+            ("deposit", 2),
+            ("dispute", 1),
+            ("withdrawal", 1)
+        ]
+    );
+    assert!(stderr.lines().any(|l| l == "rows=5"), "stderr={}", stderr);
+    assert!(
+        stderr.lines().any(|l| l == "rejected=1"),
+        "stderr={}",
+        stderr
+    );
+
+    // stdout is unaffected
+    let stdout = String::from_utf8(output.stdout).expect("stdout not utf8");
+    assert!(stdout.starts_with("client,available,held,total,locked\n"));
+}
+
+#[test]
+fn fail_on_locked_exits_with_code_3() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+dispute,1,1,
+chargeback,1,1,
+";
+    let output = run_raw(csv, &["--fail-on-locked"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8(output.stdout).expect("stdout not utf8");
+    let expected = "\
+client,available,held,total,locked
+1,0,0,0,true
+2,5,0,5,false
+";
+    assert_eq!(stdout, expected);
+
+    // nothing locked, or flag not given: normal exit
+    assert_eq!(run_raw(csv, &[]).status.code(), Some(0));
+    let csv = "type,client,tx,amount\ndeposit,1,1,10\n";
+    assert_eq!(run_raw(csv, &["--fail-on-locked"]).status.code(), Some(0));
+}
+
+#[test]
+fn grand_total_appends_summary_row() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,1,3,2.0
+withdrawal,1,4,1.5
+withdrawal,2,5,3.0
+dispute,2,2,
+";
+    let stdout = run_with_args(csv, &["--grand-total"]);
+    let expected = "\
+client,available,held,total,locked
+1,1.5,0,1.5,false
+2,0.0,2.0,2.0,false
+TOTAL,1.5,2.0,3.5,
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn since_until_skip_rows_outside_window() {
+    let csv = "\
+type,client,tx,amount,timestamp
+deposit,1,1,1,2024-01-01T00:00:00Z
+deposit,1,2,2,2024-01-02T00:00:00Z
+deposit,2,3,4,
+deposit,1,4,8,2024-01-03T00:00:00Z
+deposit,1,5,16,2024-01-04T00:00:00Z
+";
+    let stdout = run_with_args(
+        csv,
+        &[
+            "--since",
+            "2024-01-02T00:00:00Z",
+            "--until",
+            "2024-01-03T00:00:00Z",
+        ],
+    );
+    let expected = "\
+client,available,held,total,locked
+1,10,0,10,false
+2,4,0,4,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn as_of_tx_reports_balances_at_that_point() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,10,10
+deposit,2,4,5
+withdrawal,1,2,3
+dispute,2,4,
+deposit,1,1,50
+";
+    let stdout = run_with_args(csv, &["--as-of-tx", "2"]);
+    let expected = "\
+client,available,held,total,locked
+1,7,0,7,false
+2,5,0,5,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn header_only_input_prints_only_the_output_header() {
+    let stdout = run_with_csv("type,client,tx,amount\n");
+    assert_eq!(stdout, "client,available,held,total,locked\n");
+}
+
+#[test]
+fn empty_file_prints_only_the_output_header() {
+    let stdout = run_with_csv("");
+    assert_eq!(stdout, "client,available,held,total,locked\n");
+}
+
+#[test]
+fn required_schema_version_accepts_matching_input() {
+    let csv = "\
+schema_version,type,client,tx,amount
+2,deposit,1,1,10
+2,withdrawal,1,2,4
+";
+    let stdout = run_with_args(csv, &["--require-schema-version", "2"]);
+    let expected = "\
+client,available,held,total,locked
+1,6,0,6,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn required_schema_version_rejects_other_versions() {
+    let csv = "\
+schema_version,type,client,tx,amount
+1,deposit,1,1,10
+";
+    let output = run_raw(csv, &["--require-schema-version", "2"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("error: input schema version \"1\" does not match required version 2"),
+        "stderr={}",
+        stderr
+    );
+
+    // unversioned input is rejected as well
+    let output = run_raw(
+        "type,client,tx,amount\n",
+        &["--require-schema-version", "2"],
+    );
+    assert!(!output.status.success());
+}
+
+#[test]
+fn checkpoint_every_prints_intermediate_reports() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+withdrawal,1,3,4
+deposit,2,4,1
+deposit,1,5,2
+";
+    let stdout = run_with_args(csv, &["--checkpoint-every", "2"]);
+    let expected = "\
+client,available,held,total,locked
+1,10,0,10,false
+2,5,0,5,false
+
+client,available,held,total,locked
+1,6,0,6,false
+2,6,0,6,false
+
+client,available,held,total,locked
+1,8,0,8,false
+2,6,0,6,false
+";
+    assert_eq!(stdout, expected);
+
+    // with --out every report goes to the file and stdout stays empty
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let out = dir.path().join("results.csv");
+    let stdout = run_with_args(
+        csv,
+        &["--checkpoint-every", "2", "--out", out.to_str().unwrap()],
+    );
+    assert_eq!(stdout, "");
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), expected);
+}
+
+#[test]
+fn withdrawal_only_client_is_not_listed() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,2,2,5
+";
+    let stdout = run_with_csv(csv);
+    let expected = "\
+client,available,held,total,locked
+1,10,0,10,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn client_with_only_rejected_operations_is_not_listed() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,2,2,1
+deposit,2,3,-5
+deposit,2,4,
+deposit,2,1,7
+dispute,2,1,
+resolve,2,1,
+chargeback,2,1,
+dispute,2,99,
+";
+    let stdout = run_with_csv(csv);
+    let expected = "\
+client,available,held,total,locked
+1,10,0,10,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn output_scale_rounds_report_but_not_internal_math() {
+    // 1.244 - 1.235 leaves 0.009, which rounds to 0.01; rounding the
+    // amounts on the way in would have left 1.24 - 1.24 = 0.00
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,1.239
+deposit,2,2,1.244
+withdrawal,2,3,1.235
+";
+    let stdout = run_with_args(csv, &["--input-scale", "4", "--output-scale", "2"]);
+    let expected = "\
+client,available,held,total,locked
+1,1.24,0,1.24,false
+2,0.01,0,0.01,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn validate_reports_invalid_rows_without_processing() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,
+refund,1,3,5
+deposit,1,4,1.23456
+dispute,1,1,
+deposit,70000,5,1
+withdrawal,1,6,50
+";
+    let output = run_raw(csv, &["validate"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stdout = String::from_utf8(output.stdout).expect("stdout not utf8");
+    let expected = "\
+valid=3
+invalid=4
+error: line 3: transaction 2 has an invalid amount
+error: line 4: unknown transaction type \"refund\"
+error: line 5: transaction 4 has an invalid amount
+error: line 7: client id 70000 is out of range
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn validate_reads_input_like_a_run() {
+    let csv = "\
+type;client;tx;amount
+# annotated fixture
+deposit;1;1;10
+deposit;1;2;1234567890
+";
+    let args = [
+        "validate",
+        "--auto-dialect",
+        "--comment",
+        "#",
+        "--max-field-len",
+        "8",
+    ];
+    let output = run_raw(csv, &args);
+    assert_eq!(output.status.code(), Some(4));
+    let stdout = String::from_utf8(output.stdout).expect("stdout not utf8");
+    let expected = "\
+valid=1
+invalid=1
+error: line 4: field of 9 bytes exceeds the length limit
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn quiet_silences_stats_but_keeps_output() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+";
+    let output = run_raw(csv, &["--stats", "--quiet"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty(), "stderr={:?}", output.stderr);
+    let stdout = String::from_utf8(output.stdout).expect("stdout not utf8");
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+}
+
+// serves one http response with the given status line and body, returns the url
+#[cfg(feature = "http")]
+fn serve_once(status: &'static str, body: &'static str) -> String {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock server");
+    let addr = listener.local_addr().expect("mock server address");
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("accept");
+        // drain the request head before answering
+        let mut reader = BufReader::new(stream.try_clone().expect("clone stream"));
+        let mut line = String::new();
+        while reader.read_line(&mut line).expect("read request") > 2 {
+            line.clear();
+        }
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(response.as_bytes())
+            .expect("write response");
+    });
+    format!("http://{}/txns.csv", addr)
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn reads_input_from_http_url() {
+    let url = serve_once("200 OK", "type,client,tx,amount\ndeposit,1,1,10\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction_processing"))
+        .arg(&url)
+        .output()
+        .expect("failed to execute binary");
+    assert!(
+        output.status.success(),
+        "stderr={}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8(output.stdout).expect("stdout not utf8");
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http_error_status_fails_with_message() {
+    let url = serve_once("404 Not Found", "missing");
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction_processing"))
+        .arg(&url)
+        .output()
+        .expect("failed to execute binary");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("status 404"), "stderr={}", stderr);
+}
+
+#[test]
+fn account_filter_skips_other_clients() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,20
+deposit,3,3,30
+withdrawal,2,4,5
+";
+    let stdout = run_with_args(csv, &["--account-filter", "1,3"]);
+    let expected = "\
+client,available,held,total,locked
+1,10,0,10,false
+3,30,0,30,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn amount_minor_column_is_accepted() {
+    let csv = "\
+type,client,tx,amount,amount_minor
+deposit,1,1,,25000
+deposit,1,2,1.5,
+withdrawal,1,3,,5000
+deposit,2,4,1,10000
+";
+    let stdout = run_with_csv(csv);
+    let expected = "\
+client,available,held,total,locked
+1,3.5000,0,3.5000,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn auto_dialect_detects_semicolon_and_tab() {
+    let expected = "\
+client,available,held,total,locked
+1,7,0,7,false
+";
+    let semicolon = "type;client;tx;amount\ndeposit;1;1;10\nwithdrawal;1;2;3\n";
+    assert_eq!(run_with_args(semicolon, &["--auto-dialect"]), expected);
+    let tab = "type\tclient\ttx\tamount\ndeposit\t1\t1\t10\nwithdrawal\t1\t2\t3\n";
+    assert_eq!(run_with_args(tab, &["--auto-dialect"]), expected);
+
+    // without the flag the header does not parse and every row is skipped
+    assert_eq!(
+        run_with_csv(semicolon),
+        "client,available,held,total,locked\n"
+    );
+}
+
+#[test]
+fn auto_dialect_reads_a_header_longer_than_the_read_buffer() {
+    let semicolon = "type;client;tx;amount\ndeposit;1;1;10\nwithdrawal;1;2;3\n";
+    let stdout = run_with_args(semicolon, &["--auto-dialect", "--read-buffer-bytes", "8"]);
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,7,0,7,false\n"
+    );
+
+    let output = run_raw(
+        semicolon,
+        &["validate", "--auto-dialect", "--read-buffer-bytes", "8"],
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "valid=2\ninvalid=0\n"
+    );
+}
+
+#[test]
+fn auto_dialect_reads_a_header_arriving_in_pieces() {
+    use std::process::Stdio;
+
+    let bin = env!("CARGO_BIN_EXE_transaction_processing");
+    let mut child = Command::new(bin)
+        .args(["--auto-dialect", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to execute binary");
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"type;cli").unwrap();
+    stdin.flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    stdin
+        .write_all(b"ent;tx;amount\ndeposit;1;1;5.0\n")
+        .unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,5.0,0,5.0,false\n"
+    );
+}
+
+#[test]
+fn net_movement_column() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+dispute,1,1,
+deposit,2,3,2
+";
+    let stdout = run_with_args(csv, &["--net-movement", "--grand-total"]);
+    let expected = "\
+client,available,held,total,locked,net_movement
+1,-4,10,6,false,6
+2,2,0,2,false,2
+TOTAL,-2,10,8,,8
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn top_lists_largest_totals_first() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,5
+deposit,2,2,30
+deposit,3,3,10
+deposit,4,4,30
+deposit,5,5,1
+dispute,4,4,
+";
+    // client 4's funds are held but still count towards its total
+    let stdout = run_with_args(csv, &["--top", "2"]);
+    let expected = "\
+client,available,held,total,locked
+2,30,0,30,false
+4,0,30,30,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn dump_transactions_writes_the_recorded_table() {
+    let csv = "\
+type,client,tx,amount
+deposit,2,5,3.5
+deposit,1,1,10
+withdrawal,1,2,4
+withdrawal,1,3,100
+dispute,2,5,
+";
+    let dump = tempfile::tempdir().expect("create temp dir");
+    let path = dump.path().join("transactions.csv");
+    run_with_args(csv, &["--dump-transactions", path.to_str().unwrap()]);
+
+    let dumped = std::fs::read_to_string(&path).expect("read dump");
+    let expected = "\
+tx,client,kind,amount,disputed
+1,1,deposit,10,false
+2,1,withdrawal,4,false
+5,2,deposit,3.5,true
+";
+    assert_eq!(dumped, expected);
+}
+
+#[test]
+fn detect_truncation_reports_a_cut_off_last_row() {
+    let truncated = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5
+withdr";
+    let output = run_raw(truncated, &["--detect-truncation"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("error: input looks truncated, last record 3 is incomplete"),
+        "stderr={}",
+        stderr
+    );
+
+    // without the flag the partial row is dropped like any malformed row
+    let stdout = run_with_csv(truncated);
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,15,0,15,false\n"
+    );
+
+    // a malformed row in the middle is not a truncation
+    let complete = "\
+type,client,tx,amount
+deposit,1,x,10
+deposit,1,2,5
+";
+    let stdout = run_with_args(complete, &["--detect-truncation"]);
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,5,0,5,false\n"
+    );
+
+    // short rows are complete, with or without a final line break
+    for ending in ["", "\n"] {
+        let csv = format!(
+            "type,client,tx,amount\ndeposit,1,1,10\ndispute,1,1{}",
+            ending
+        );
+        let stdout = run_with_args(&csv, &["--detect-truncation"]);
+        assert_eq!(
+            stdout,
+            "client,available,held,total,locked\n1,0,10,10,false\n"
+        );
+    }
+}
+
+#[test]
+fn comment_lines_are_skipped() {
+    let csv = "\
+# fixture: deposit then partial withdrawal
+type,client,tx,amount
+deposit,1,1,10
+# the withdrawal below leaves 6
+withdrawal,1,2,4
+#deposit,1,3,100
+";
+    let stdout = run_with_args(csv, &["--comment", "#"]);
+    let expected = "\
+client,available,held,total,locked
+1,6,0,6,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn expect_compares_report_with_golden_file() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,3
+";
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let golden = dir.path().join("expected.csv");
+
+    std::fs::write(
+        &golden,
+        "client,available,held,total,locked\n1,10,0,10,false\n2,3,0,3,false\n",
+    )
+    .expect("write expected file");
+    run_with_args(csv, &["--expect", golden.to_str().unwrap()]);
+
+    std::fs::write(
+        &golden,
+        "client,available,held,total,locked\n1,10,0,10,false\n2,4,0,4,false\n",
+    )
+    .expect("write expected file");
+    let output = run_raw(csv, &["--expect", golden.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("in 1 line(s)"), "stderr={}", stderr);
+    assert!(stderr.contains("line 3:"), "stderr={}", stderr);
+    assert!(
+        stderr.contains("actual:   2,3,0,3,false"),
+        "stderr={}",
+        stderr
+    );
+}
+
+#[test]
+fn interactive_prints_each_update_immediately() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    let bin = env!("CARGO_BIN_EXE_transaction_processing");
+    let mut child = Command::new(bin)
+        .arg("--interactive")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to execute binary");
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut read_line = || {
+        let mut line = String::new();
+        stdout.read_line(&mut line).expect("read stdout");
+        line
+    };
+
+    // stdin stays open, so each line must arrive before the input ends
+    stdin.write_all(b"type,client,tx,amount\n").unwrap();
+    stdin.write_all(b"deposit,1,1,2.0\n").unwrap();
+    stdin.flush().unwrap();
+    assert_eq!(read_line(), "client,available,held,total,locked\n");
+    assert_eq!(read_line(), "1,2.0,0,2.0,false\n");
+
+    stdin.write_all(b"deposit,2,2,1.0\n").unwrap();
+    stdin.flush().unwrap();
+    assert_eq!(read_line(), "2,1.0,0,1.0,false\n");
+
+    stdin.write_all(b"withdrawal,1,3,0.5\n").unwrap();
+    stdin.flush().unwrap();
+    assert_eq!(read_line(), "1,1.5,0,1.5,false\n");
+
+    // a row that changes nothing prints nothing
+    stdin.write_all(b"withdrawal,2,4,5.0\n").unwrap();
+    stdin.write_all(b"deposit,2,5,0.5\n").unwrap();
+    stdin.flush().unwrap();
+    assert_eq!(read_line(), "2,1.5,0,1.5,false\n");
+
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+    assert_eq!(read_line(), "");
+}
+
+#[test]
+fn interactive_rejects_whole_report_options() {
+    for flag in [
+        &["--tail", "1"][..],
+        &["--top", "1"],
+        &["--grand-total"],
+        &["--group-output-by-currency"],
+    ] {
+        let output = run_raw(
+            "type,client,tx,amount\n",
+            &[&["--interactive"], flag].concat(),
+        );
+        assert!(!output.status.success(), "{:?}", flag);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("--interactive cannot be combined"),
+            "stderr={}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn duplicate_header_column_is_rejected() {
+    let csv = "\
+type,client,tx,amount,amount
+deposit,1,1,10,5
+";
+    let output = run_raw(csv, &[]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("error: column amount appears more than once in the header"),
+        "stderr={}",
+        stderr
+    );
+
+    let output = run_raw(csv, &["validate"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("error: line 1: column amount appears more than once"));
+}
+
+#[test]
+fn out_with_gz_suffix_writes_compressed_report() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+withdrawal,1,3,0.25
+";
+    let expected = "\
+client,available,held,total,locked
+1,0.75,0,0.75,false
+2,2.0,0,2.0,false
+";
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let gz = dir.path().join("results.csv.gz");
+    let stdout = run_with_args(csv, &["--out", gz.to_str().unwrap()]);
+    assert_eq!(stdout, "");
+
+    let mut report = String::new();
+    GzDecoder::new(std::fs::File::open(&gz).expect("open gz"))
+        .read_to_string(&mut report)
+        .expect("decompress report");
+    assert_eq!(report, expected);
+
+    // any other suffix is written as is
+    let plain = dir.path().join("results.csv");
+    run_with_args(csv, &["--out", plain.to_str().unwrap()]);
+    assert_eq!(std::fs::read_to_string(&plain).unwrap(), expected);
+}
+
+#[test]
+fn emit_empty_on_no_clients_drops_the_header() {
+    let csv = "\
+type,client,tx,amount
+dispute,1,42,
+resolve,1,42,
+";
+    let stdout = run_with_args(csv, &["--emit-empty-on-no-clients"]);
+    assert_eq!(stdout, "");
+
+    // with any account the report is unchanged
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,2.0
+";
+    let stdout = run_with_args(csv, &["--emit-empty-on-no-clients"]);
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,2.0,0,2.0,false\n"
+    );
+}
+
+#[test]
+fn timings_report_each_phase() {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=1000 {
+        csv.push_str(&format!("deposit,{},{},1.5\n", tx % 10, tx));
+    }
+    for args in [
+        &["--stats", "--timings"][..],
+        &["--stats", "--timings", "--fast-path"],
+    ] {
+        let output = run_raw(&csv, args);
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).expect("stderr not utf8");
+        let timings: Vec<(&str, u64)> = stderr
+            .lines()
+            .filter_map(|line| line.strip_prefix("time."))
+            .filter_map(|line| line.split_once('='))
+            .map(|(phase, micros)| (phase, micros.parse().expect("micros")))
+            .collect();
+        let phases: Vec<&str> = timings.iter().map(|(phase, _)| *phase).collect();
+        assert_eq!(
+            phases,
+            ["parse_us", "apply_us", "output_us"],
+            "args={:?}",
+            args
+        );
+    }
+
+    let output = run_raw(&csv, &["--timings"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--timings requires --stats"));
+}
+
+#[test]
+fn piped_output_defaults_to_csv() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,1.0
+";
+    let expected = "\
+client,available,held,total,locked
+1,1.0,0,1.0,false
+";
+    // stdout is a pipe here, so auto detection must pick CSV
+    assert_eq!(run_with_csv(csv), expected);
+    assert_eq!(run_with_args(csv, &["--output", "csv"]), expected);
+
+    let table = run_with_args(csv, &["--output", "table"]);
+    assert_ne!(table, expected);
+    assert!(table.contains("client"), "table={}", table);
+    assert!(table.contains("1.0"), "table={}", table);
+}
+
+#[test]
+fn table_output_contains_expected_values() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,2.5
+dispute,2,2,
+";
+    let table = run_with_args(csv, &["--output", "table", "--grand-total"]);
+    let rows: Vec<Vec<&str>> = table
+        .lines()
+        .filter(|line| line.starts_with('|'))
+        .map(|line| {
+            line.split('|')
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        rows,
+        vec![
+            vec!["client", "available", "held", "total", "locked"],
+            vec!["1", "10", "0", "10", "false"],
+            vec!["2", "0.0", "2.5", "2.5", "false"],
+            vec!["TOTAL", "10", "2.5", "12.5"],
+        ]
+    );
+}
+
+#[test]
+fn fail_on_reject_exits_non_zero_for_any_unapplied_row() {
+    let clean = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+dispute,1,1,
+resolve,1,1,
+";
+    let output = run_raw(clean, &["--fail-on-reject"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    // each of these rows is dropped for a different reason
+    for bad_row in [
+        "withdrawal,1,3,100",
+        "deposit,1,3,abc",
+        "dispute,1,99,",
+        "bonus,1,3,1",
+        "deposit,x,3,1",
+    ] {
+        let csv = format!("{}{}\n", clean, bad_row);
+        let output = run_raw(&csv, &["--fail-on-reject"]);
+        assert_eq!(output.status.code(), Some(6), "row={}", bad_row);
+        // the report is still written
+        assert!(String::from_utf8_lossy(&output.stdout).contains("1,6,0,6,false"));
+    }
+}
+
+#[test]
+fn fail_on_reject_counts_rows_left_pending() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,99,
+";
+    let output = run_raw(csv, &["--pending-limit", "10", "--fail-on-reject"]);
+    assert_eq!(output.status.code(), Some(6));
+    let output = run_raw(csv, &["--pending-limit", "10", "--stats"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("pending=1"));
+}
+
+#[test]
+fn dump_state_writes_a_json_document() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+";
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let path = dir.path().join("state.json");
+    run_with_args(csv, &["--dump-state", path.to_str().unwrap()]);
+
+    let state = std::fs::read_to_string(&path).expect("read state");
+    for key in [
+        "\"version\"",
+        "\"config\"",
+        "\"accounts\"",
+        "\"transactions\"",
+    ] {
+        assert!(state.contains(key), "missing {} in {}", key, state);
+    }
+}
+
+#[test]
+fn dispute_agents_flag_allows_third_party_disputes() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,2,1,
+";
+    let stdout = run_with_args(csv, &["--dispute-agents", "2,3"]);
+    let expected = "\
+client,available,held,total,locked
+1,0,10,10,false
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn checksum_is_stable_and_follows_the_input() {
+    let checksum = |csv: &str| {
+        let output = run_raw(csv, &["--checksum"]);
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let line = stderr
+            .lines()
+            .find_map(|line| line.strip_prefix("checksum="))
+            .expect("no checksum printed")
+            .to_string();
+        assert_eq!(line.len(), 64);
+        line
+    };
+    let csv = "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,2,2,3.0\nwithdrawal,1,3,0.5\n";
+    let first = checksum(csv);
+    assert_eq!(first, checksum(csv));
+
+    let changed = csv.replace("0.5", "0.25");
+    assert_ne!(first, checksum(&changed));
+
+    // --quiet silences it
+    let output = run_raw(csv, &["--checksum", "--quiet"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("checksum="));
+}
+
+#[test]
+fn explain_lists_every_row_of_a_transaction() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,7,10.0
+deposit,1,8,1.0
+dispute,1,7,
+withdrawal,1,9,2.0
+resolve,1,7,
+";
+    let output = run_raw(csv, &["--explain", "7"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "tx 7: deposit client=1 applied client 1 available=10.0 held=0",
+            "tx 7: dispute client=1 applied client 1 available=-10.0 held=10.0",
+            "tx 7: resolve client=1 applied client 1 available=10.0 held=-10.0",
+        ]
+    );
+    // the report is printed as usual, the withdrawal hit the held funds
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1,11.0,0.0,11.0,false"));
+}
+
+#[test]
+fn held_detail_lists_the_disputes_making_up_held() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,2,2.5
+deposit,1,3,4.0
+deposit,2,4,1.0
+dispute,1,3,
+dispute,1,1,
+dispute,2,4,
+resolve,2,4,
+";
+    let detail = run_with_args(csv, &["--output", "held-detail"]);
+    assert_eq!(detail, "client,tx,held\n1,1,10.0\n1,3,4.0\n");
+
+    let report = run_with_csv(csv);
+    assert!(report.contains("1,2.5,14.0,16.5,false"));
+    let held: rust_decimal::Decimal = detail
+        .lines()
+        .skip(1)
+        .map(|line| {
+            line.rsplit(',')
+                .next()
+                .unwrap()
+                .parse::<rust_decimal::Decimal>()
+                .unwrap()
+        })
+        .sum();
+    assert_eq!(held.to_string(), "14.0");
+}
+
+#[test]
+fn unbalanced_quote_only_costs_its_own_row() {
+    let csv = "\
+type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,\"5.0
+deposit,1,3,2.0
+deposit,2,4,3.0
+";
+    for args in [&["--stats"][..], &["--stats", "--fast-path"]] {
+        let output = run_raw(csv, args);
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("1,3.0,0,3.0,false"), "{}", stdout);
+        assert!(stdout.contains("2,3.0,0,3.0,false"), "{}", stdout);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("rows=4"), "{}", stderr);
+        assert!(stderr.contains("malformed=1"), "{}", stderr);
+    }
+}
+
+#[test]
+fn ids_at_the_top_of_their_range_round_trip() {
+    let csv = "\
+type,client,tx,amount
+deposit,65535,18446744073709551615,5.0
+deposit,65535,18446744073709551615,5.0
+deposit,65535,18446744073709551614,2.0
+withdrawal,65535,18446744073709551613,1.0
+dispute,65535,18446744073709551615,
+deposit,65536,1,1.0
+deposit,1,18446744073709551616,1.0
+";
+    let dump = tempfile::tempdir().expect("create temp dir");
+    let path = dump.path().join("transactions.csv");
+    for fast_path in [false, true] {
+        let mut args = vec!["--stats", "--dump-transactions", path.to_str().unwrap()];
+        if fast_path {
+            args.push("--fast-path");
+        }
+        let output = run_raw(csv, &args);
+        assert!(output.status.success());
+        // the repeated max tx is a duplicate, the dispute holds its 5.0
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(
+            stdout,
+            "client,available,held,total,locked\n65535,1.0,5.0,6.0,false\n"
+        );
+        // a client id past u16 is rejected, a tx id past u64 does not parse
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("rejected=1"), "{}", stderr);
+        assert!(stderr.contains("malformed=1"), "{}", stderr);
+
+        let dumped = std::fs::read_to_string(&path).expect("read dump");
+        let expected = "\
+tx,client,kind,amount,disputed
+18446744073709551613,65535,withdrawal,1.0,false
+18446744073709551614,65535,deposit,2.0,false
+18446744073709551615,65535,deposit,5.0,true
+";
+        assert_eq!(dumped, expected);
+    }
+}
+
+#[test]
+fn emit_config_header_records_the_effective_config() {
+    let csv = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    let stdout = run_with_args(
+        csv,
+        &[
+            "--emit-config-header",
+            "--chargeback-mode",
+            "refund",
+            "--overdraft-limit",
+            "50",
+            "--dispute-agents",
+            "9,3,7",
+            "--output-scale",
+            "2",
+        ],
+    );
+    let (comments, report): (Vec<&str>, Vec<&str>) =
+        stdout.lines().partition(|line| line.starts_with('#'));
+    assert!(comments.contains(&"# chargeback_mode=\"Refund\""));
+    assert!(comments.contains(&"# overdraft_limit=\"50\""));
+    assert!(comments.contains(&"# dispute_agents=[3,7,9]"));
+    assert!(comments.contains(&"# strict=false"));
+    assert_eq!(comments.last(), Some(&"# output_scale=2"));
+    // the comments come first, the report is unchanged
+    assert!(stdout.starts_with("# "));
+    assert_eq!(
+        report,
+        ["client,available,held,total,locked", "1,1.0,0,1.0,false"]
+    );
+
+    // nothing is added by default
+    assert!(!run_with_csv(csv).contains('#'));
+}
+
+#[test]
+fn group_output_by_currency_sums_each_currency() {
+    let csv = "\
+type,client,tx,amount,currency
+deposit,1,1,10.0,EUR
+deposit,2,2,5.0,USD
+deposit,3,3,2.5,EUR
+dispute,3,3,
+deposit,4,4,1.0,
+";
+    let stdout = run_with_args(csv, &["--group-output-by-currency", "--grand-total"]);
+    let expected = "\
+client,available,held,total,locked
+1,10.0,0,10.0,false
+2,5.0,0,5.0,false
+3,0.0,2.5,2.5,false
+4,1.0,0,1.0,false
+TOTAL EUR,10.0,2.5,12.5,
+TOTAL USD,5.0,0,5.0,
+TOTAL,16.0,2.5,18.5,
+";
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn currency_mismatch_is_rejected_on_both_paths() {
+    let csv = "\
+type,client,tx,amount,currency
+deposit,1,1,10.0,EUR
+deposit,1,2,5.0,USD
+deposit,2,3,1.0,USD
+withdrawal,2,4,0.5,
+";
+    for args in [&["--stats"][..], &["--stats", "--fast-path"]] {
+        let output = run_raw(csv, args);
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(
+            stdout,
+            "client,available,held,total,locked\n1,10.0,0,10.0,false\n2,0.5,0,0.5,false\n"
+        );
+        assert!(String::from_utf8_lossy(&output.stderr).contains("rejected=1"));
+    }
+}